@@ -1,11 +0,0 @@
-#[macro_use]
-extern crate futures;
-
-pub mod rx_wrapper;
-pub mod send_all;
-
-#[cfg(test)]
-mod tests {
-    #[test]
-    fn it_works() {}
-}
@@ -1,33 +1,24 @@
-extern crate futures;
-extern crate libc;
-extern crate mio;
-extern crate tokio_core;
 #[macro_use]
 extern crate log;
-extern crate futures_addition;
 
-use std::io::{self, Read, Write};
-use std::os::unix::io::{FromRawFd, RawFd};
+use std::io;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 use std::os::unix::process::CommandExt;
+use std::pin::Pin;
 use std::process;
 use std::ptr;
+use std::task::{ready, Context, Poll};
 
-use futures::sync::oneshot;
-use futures::{Async, AsyncSink, Future, Poll, Sink, StartSend, Stream};
-use mio::unix::{EventedFd, UnixReady};
-use mio::{Evented, PollOpt, Ready, Token};
-use tokio_core::reactor::{Handle, PollEvented};
-
-use futures_addition::send_all::HasItem;
+use tokio::io::unix::AsyncFd;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 
 #[cfg(test)]
 mod tests {
     use super::*;
     extern crate env_logger;
-    use futures::Future;
-    use tokio_core::reactor::Core;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-    const STIMULI: [u8; 5] = ['h' as u8, 'e' as u8, 'j' as u8, '\n' as u8, '\x04' as u8];
+    const STIMULI: [u8; 5] = [b'h', b'e', b'j', b'\n', b'\x04'];
 
     fn printfds(prefix: &str) {
         use std::fs;
@@ -40,50 +31,31 @@ mod tests {
         }
     }
 
-    #[test]
-    fn it_works() {
+    #[tokio::test]
+    async fn it_works() {
         env_logger::init().unwrap();
-        let mut core = Core::new().unwrap();
-        let handle = core.handle();
 
         let pty = crate::Pty::new();
-        let mut child = pty.spawn(process::Command::new("cat"), &handle).unwrap();
+        let mut child = pty.spawn(process::Command::new("cat")).unwrap();
 
-        let mut stim = Vec::new();
-        for c in STIMULI.iter() {
-            stim.push(c.clone());
+        if log_enabled!(log::LogLevel::Debug) {
+            printfds("after spawn");
         }
 
-        let output = child
+        let mut input = child.input().take().unwrap();
+        input.write_all(&STIMULI).await.unwrap();
+
+        // The output ends when cat exits after ^D
+        let mut output = Vec::new();
+        child
             .output()
             .take()
             .unwrap()
-            .for_each(|x| {
-                print!("OUT {}", String::from_utf8(x).unwrap());
-                Ok(())
-            })
-            .then(|r| match r {
-                Err(PtyStreamError::IoError(e)) => Err(e),
-                Err(PtyStreamError::ProcessDied) => Ok(()),
-                _ => Ok(()),
-            });
-
-        let input = child
-            .input()
-            .take()
-            .unwrap()
-            .send(stim)
-            .and_then(|f| f.flush())
-            .then(|r| match r {
-                Err(PtySinkError::IoError(e)) => Err(e),
-                _ => Ok(()),
-            });
-
-        if log_enabled!(log::LogLevel::Debug) {
-            printfds("before core");
-        }
-
-        core.run(input.join(output)).unwrap();
+            .read_to_end(&mut output)
+            .await
+            .unwrap();
+        print!("OUT {}", String::from_utf8_lossy(&output));
+        assert!(output.ends_with(b"hej\r\n"), "{:?}", output);
     }
 }
 
@@ -104,9 +76,26 @@ impl From<u16> for Columns {
     }
 }
 
+/// User and groups to run the child process as.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Credentials {
+    pub uid: libc::uid_t,
+    pub gid: libc::gid_t,
+    /// Supplementary groups
+    pub groups: Vec<libc::gid_t>,
+}
+
 pub struct Pty {
-    master: RawFd,
+    // Both are closed on drop unless they have been handed over to a child
+    master: Option<RawFd>,
     slave: Option<RawFd>,
+    credentials: Option<Credentials>,
+}
+
+impl Default for Pty {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Pty {
@@ -119,21 +108,35 @@ impl Pty {
         //}
 
         Pty {
-            master: master as RawFd,
+            master: Some(master as RawFd),
             slave: Some(slave as RawFd),
+            credentials: None,
         }
     }
 
-    pub fn spawn(mut self, mut command: process::Command, handle: &Handle) -> io::Result<Child> {
+    /// Run the child as another user. The slave side of the pty is handed over to that user when
+    /// the child is spawned.
+    pub fn set_credentials(&mut self, credentials: Credentials) {
+        self.credentials = Some(credentials);
+    }
+
+    /// Spawns `command` on the slave side of the pty. Has to be called within a tokio runtime.
+    pub fn spawn(mut self, mut command: process::Command) -> io::Result<Child> {
         debug!("spawning {:?}", command);
-        let (master, slave) = (self.master, self.slave.take().unwrap());
+        let (master, slave) = (self.master.unwrap(), self.slave.unwrap());
+        let credentials = self.credentials.take();
+        if let Some(ref credentials) = credentials {
+            // The child must be able to open its controlling terminal, keep the group (tty)
+            cvt(unsafe { libc::fchown(slave, credentials.uid, !0) })?;
+            cvt(unsafe { libc::fchmod(slave, 0o620) })?;
+        }
         // Box up the slave interface so that it is moved safely to the child process.
         let child_slave = Box::new(slave);
-        // process::Stdio will take care of closing slave. It uses FileDesc::drop internally which
-        // ignores errors on libc::close. We can therefore safely create copies of slave.
-        let childin = unsafe { process::Stdio::from_raw_fd(slave) };
-        let childout = unsafe { process::Stdio::from_raw_fd(slave) };
-        let slave = unsafe { process::Stdio::from_raw_fd(slave) };
+        // process::Stdio takes ownership of the fd it is given and closes it on drop, so every
+        // stdio handle needs its own copy of slave.
+        let childin = unsafe { process::Stdio::from_raw_fd(cvt(libc::dup(slave))?) };
+        let childout = unsafe { process::Stdio::from_raw_fd(cvt(libc::dup(slave))?) };
+        let slave = unsafe { process::Stdio::from_raw_fd(self.slave.take().unwrap()) };
         unsafe {
             command
                 .stdin(childin)
@@ -148,6 +151,13 @@ impl Pty {
                     // Slave and master are not needed anymore
                     cvt(libc::close(slave))?;
                     cvt(libc::close(master))?;
+                    // Drop privileges last, everything above might need them
+                    if let Some(ref credentials) = credentials {
+                        let groups = &credentials.groups;
+                        cvt(libc::setgroups(groups.len() as _, groups.as_ptr()))?;
+                        cvt(libc::setgid(credentials.gid))?;
+                        cvt(libc::setuid(credentials.uid))?;
+                    }
                     // Reset all signal handlers to default
                     libc::signal(libc::SIGCHLD, libc::SIG_DFL);
                     libc::signal(libc::SIGHUP, libc::SIG_DFL);
@@ -162,77 +172,83 @@ impl Pty {
 
         let child = command.spawn()?;
 
-        let master2 = cvt(unsafe { libc::dup(master) })?;
-
-        let (stream_done_tx, stream_done_rx) = oneshot::channel::<i32>();
-        let io = stdio(master, handle);
-        let output = PtyStream::new(
-            io.expect("Failed to create EventedFd for output").unwrap(),
-            stream_done_rx,
-        );
-
-        let (sink_done_tx, sink_done_rx) = oneshot::channel::<i32>();
-        let io = stdio(master2, handle);
-        let input = PtySink::new(
-            io.expect("Failed to create EventedFd for input").unwrap(),
-            sink_done_rx,
-        );
-
+        // From here on the fds are closed when the handles owning them are dropped
+        self.master = None;
+        let master = Fd(master);
+        let output = PtyStream::new(Fd(cvt(unsafe { libc::dup(master.0) })?))?;
+        let input = PtySink::new(Fd(cvt(unsafe { libc::dup(master.0) })?))?;
         let child = Child {
             inner: child,
-            master: (master, master2),
+            master,
             input: Some(input),
             output: Some(output),
-            sink_done: Some(sink_done_tx),
-            stream_done: Some(stream_done_tx),
         };
         Ok(child)
     }
 }
 
+impl Drop for Pty {
+    fn drop(&mut self) {
+        for fd in self.master.take().into_iter().chain(self.slave.take()) {
+            let _ = unsafe { libc::close(fd) };
+        }
+    }
+}
+
 pub struct Child {
     inner: process::Child,
-    master: (RawFd, RawFd),
+    // Kept to change the window size
+    master: Fd,
     input: Option<PtySink>,
     output: Option<PtyStream>,
-    sink_done: Option<oneshot::Sender<i32>>,
-    stream_done: Option<oneshot::Sender<i32>>,
 }
 
 impl Child {
     pub fn wait(&mut self) -> io::Result<std::process::ExitStatus> {
         trace!("wait for child");
-        //self.sink_done.take().unwrap().send(1).unwrap();
-        //self.stream_done.take().unwrap().send(1).unwrap();
-        let sink = self.sink_done.take().unwrap();
-        match sink.send(1) {
-            Ok(()) => trace!("killing sink"),
-            Err(e) => trace!("sink already deallocated {:?}", e),
-        }
-        let stream = self.stream_done.take().unwrap();
-        match stream.send(1) {
-            Ok(()) => trace!("killing stream"),
-            Err(e) => trace!("stream already deallocated {:?}", e),
-        }
         self.inner.wait()
     }
 
+    /// Reaps the child if it has exited, without blocking.
+    pub fn try_wait(&mut self) -> io::Result<Option<std::process::ExitStatus>> {
+        self.inner.try_wait()
+    }
+
     pub fn kill(&mut self) -> io::Result<()> {
         self.inner.kill()
     }
 
+    /// Send `signal` to the child process.
+    pub fn signal(&mut self, signal: libc::c_int) -> io::Result<()> {
+        cvt(unsafe { libc::kill(self.inner.id() as libc::pid_t, signal) }).map(|_| ())
+    }
+
+    /// Send `signal` to every process in the child's process group. The child is a session
+    /// leader so the group id is the same as its pid.
+    pub fn signal_group(&mut self, signal: libc::c_int) -> io::Result<()> {
+        signal_process_group(self.inner.id(), signal)
+    }
+
     pub fn id(&self) -> u32 {
         self.inner.id()
     }
 
     pub fn set_window_size(&mut self, rows: Rows, columns: Columns) {
-        info!("set rows: {:?}, cols: {:?}", rows, columns);
-        let mut ws = get_winsize(self.master.0).unwrap();
+        debug!("set rows: {:?}, cols: {:?}", rows, columns);
+        let mut ws = match get_winsize(self.master.0) {
+            Ok(ws) => ws,
+            Err(e) => {
+                warn!("Failed to get window size: {}", e);
+                return;
+            }
+        };
         let Rows(ws_row) = rows;
         let Columns(ws_col) = columns;
         ws.ws_row = ws_row;
         ws.ws_col = ws_col;
-        set_winsize(self.master.0, &ws).expect("Failed to set window size");
+        if let Err(e) = set_winsize(self.master.0, &ws) {
+            warn!("Failed to set window size: {}", e);
+        }
     }
 
     pub fn output(&mut self) -> &mut Option<PtyStream> {
@@ -244,18 +260,6 @@ impl Child {
     }
 }
 
-impl Drop for Child {
-    fn drop(&mut self) {
-        // Ignore error for same reason as FileDesc in rust stdlib.
-        debug!(
-            "Dropping stdin/stdout fds {} {}",
-            self.master.0, self.master.1
-        );
-        let _ = unsafe { libc::close(self.master.0) };
-        let _ = unsafe { libc::close(self.master.1) };
-    }
-}
-
 /// Get raw fds for master/slave ends of a new pty
 #[cfg(target_os = "linux")]
 fn openpty(rows: u16, cols: u16) -> (RawFd, RawFd) {
@@ -298,181 +302,97 @@ pub fn set_winsize(fd: RawFd, ws: &libc::winsize) -> io::Result<()> {
  *
  */
 
-#[derive(Debug)]
-pub enum PtyStreamError {
-    IoError(io::Error),
-    ProcessDied,
-}
-
-impl From<io::Error> for PtyStreamError {
-    fn from(error: io::Error) -> Self {
-        PtyStreamError::IoError(error)
-    }
-}
-
+/// The output of the child. It ends when the slave side of the pty is closed, which happens when
+/// the child and everything it started have exited.
 pub struct PtyStream {
-    ptyio: PtyIo,
-    done: oneshot::Receiver<i32>,
+    io: AsyncFd<Fd>,
 }
 
 impl PtyStream {
-    pub fn new(ptyio: PtyIo, done: oneshot::Receiver<i32>) -> PtyStream {
-        PtyStream {
-            ptyio: ptyio,
-            done: done,
+    fn new(fd: Fd) -> io::Result<PtyStream> {
+        set_nonblock(fd.0)?;
+        // Fd owns the descriptor and only closes it on drop
+        let io = unsafe { AsyncFd::register(fd)? };
+        Ok(PtyStream { io })
+    }
+
+    /// Reads what is available right now, without waiting for more.
+    pub fn try_read(&self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.io.get_ref().read(buf) {
+            Err(ref e) if e.raw_os_error() == Some(libc::EIO) => Ok(0),
+            res => res,
         }
     }
 }
 
-impl Stream for PtyStream {
-    type Item = Vec<u8>;
-    type Error = PtyStreamError;
-
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let mut buf = [0 as u8; 2048];
-        match self.done.poll() {
-            Ok(Async::Ready(..)) => return Err(io::Error::new(io::ErrorKind::Other, "died").into()),
-            _ => (),
-        }
-        match self.ptyio.read(&mut buf) {
-            Ok(len) => {
-                trace!("Read {} bytes from {:?}", len, self.ptyio);
-                let mut vec = Vec::new();
-                for i in 0..len {
-                    vec.push(buf[i]);
+impl AsyncRead for PtyStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.io.poll_read_ready(cx))?;
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|io| io.get_ref().read(unfilled)) {
+                Ok(Ok(len)) => {
+                    trace!("Read {} bytes from {:?}", len, self.io.get_ref());
+                    buf.advance(len);
+                    return Poll::Ready(Ok(()));
                 }
-                //println!("{:?}", vec);
-                return Ok(Async::Ready(Some(vec)));
-            }
-            Err(e) => {
-                if e.kind() == io::ErrorKind::WouldBlock {
-                    return Ok(Async::NotReady);
+                Ok(Err(ref e)) if e.raw_os_error() == Some(libc::EIO) => {
+                    // The process exited
+                    debug!("Failed to read from {:?}: {:?}", self.io.get_ref(), e);
+                    return Poll::Ready(Ok(()));
                 }
-                if e.kind() == io::ErrorKind::Other {
-                    // Process probably exited
-                    warn!(
-                        "Failed to read from {:?}: {:?}, process died?",
-                        self.ptyio, e
-                    );
-                    return Err(PtyStreamError::ProcessDied);
-                    //return Ok(Async::Ready(None));
+                Ok(Err(e)) => {
+                    warn!("Failed to read: {:?}", e);
+                    return Poll::Ready(Err(e));
                 }
-                warn!("Failed to read: {:?}", e);
-                return Err(e.into());
+                Err(_would_block) => continue,
             }
         }
     }
 }
 
-#[derive(Debug)]
-pub enum PtySinkError<T> {
-    IoError(io::Error),
-    TryAgain(T),
-}
-
-impl<T> From<io::Error> for PtySinkError<T> {
-    fn from(error: io::Error) -> Self {
-        PtySinkError::IoError(error)
-    }
-}
-
-impl<T> HasItem<T> for PtySinkError<T> {
-    fn item(self) -> Option<T> {
-        match self {
-            PtySinkError::TryAgain(item) => Some(item),
-            _ => None,
-        }
-    }
-}
-
+/// The input of the child.
 pub struct PtySink {
-    pub ptyin: PtyIo,
-    buf: Vec<u8>,
-    done: oneshot::Receiver<i32>,
+    io: AsyncFd<Fd>,
 }
 
 impl PtySink {
-    pub fn new(ptyin: PtyIo, done: oneshot::Receiver<i32>) -> PtySink {
-        PtySink {
-            ptyin: ptyin,
-            buf: Vec::new(),
-            done: done,
-        }
-    }
-}
-
-impl Sink for PtySink {
-    type SinkItem = Vec<u8>;
-    type SinkError = PtySinkError<Self::SinkItem>;
-
-    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
-        let orig_len = item.len();
-        let written_len;
-        if orig_len == 0 {
-            return Ok(AsyncSink::Ready);
-        }
-        match self.done.poll() {
-            Ok(Async::Ready(..)) => return Err(PtySinkError::TryAgain(item)),
-            _ => (),
-        }
-        {
-            match self.ptyin.write(item.as_slice()) {
-                Ok(len) => {
-                    trace!("wrote {} to {:?}", len, self.ptyin);
-                    std::io::Write::flush(self.ptyin.get_mut()).expect("failed to flush");
-                    written_len = len;
-                }
-                Err(e) => {
-                    if e.kind() == io::ErrorKind::WouldBlock {
-                        trace!("not ready to write: {:?}", e);
-                        return Ok(AsyncSink::NotReady(item));
-                    }
-                    warn!("start_send(): Failed to write: {:?}", e);
-                    //return Err(From::from(e));
-                    return Err(PtySinkError::TryAgain(item));
+    fn new(fd: Fd) -> io::Result<PtySink> {
+        set_nonblock(fd.0)?;
+        // Fd owns the descriptor and only closes it on drop
+        let io = unsafe { AsyncFd::register(fd)? };
+        Ok(PtySink { io })
+    }
+}
+
+impl AsyncWrite for PtySink {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.io.poll_write_ready(cx))?;
+            match guard.try_io(|io| io.get_ref().write(buf)) {
+                Ok(res) => {
+                    trace!("wrote {:?} to {:?}", res, self.io.get_ref());
+                    return Poll::Ready(res);
                 }
+                Err(_would_block) => continue,
             }
         }
-        self.buf.clear();
-        if written_len != orig_len {
-            for x in item[written_len..orig_len - 1].iter() {
-                self.buf.push(*x);
-            }
-        }
-        Ok(AsyncSink::Ready)
     }
 
-    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        let orig_len = self.buf.len();
-        let mut res = Ok(Async::NotReady);
-        if orig_len == 0 {
-            return Ok(Async::Ready(()));
-        }
-        let mut written_len = 0;
-        {
-            trace!("PC Will write to {:?}", self.ptyin);
-            match self.ptyin.write(self.buf.as_slice()) {
-                Ok(len) => {
-                    trace!("wrote {}", len);
-                    written_len = len;
-                    //println!("wrote {} bytes", len);
-                    std::io::Write::flush(self.ptyin.get_mut()).expect("failed to flush");
-                    if len == orig_len {
-                        res = Ok(Async::Ready(()));
-                    }
-                }
-                Err(e) => {
-                    //self.buf.append(copy.drain(..).collect().iter());
-                    if e.kind() != io::ErrorKind::WouldBlock {
-                        warn!("poll_complete(): Failed to write: {:?}", e);
-                        //res = Ok(Async::Ready(None));
-                        res = Err(From::from(e));
-                    }
-                }
-            }
-        }
-        self.buf.drain(0..written_len);
-        res
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
     }
 }
 
@@ -480,6 +400,18 @@ pub fn set_sessionid() -> io::Result<()> {
     unsafe { cvt(libc::setsid()).map(|_| ()) }
 }
 
+pub fn signal_process_group(pgid: u32, signal: libc::c_int) -> io::Result<()> {
+    unsafe { cvt(libc::killpg(pgid as libc::pid_t, signal)).map(|_| ()) }
+}
+
+/// Returns true if any process is left in the process group `pgid`.
+pub fn process_group_alive(pgid: u32) -> bool {
+    match signal_process_group(pgid, 0) {
+        Ok(()) => true,
+        Err(e) => e.raw_os_error() == Some(libc::EPERM),
+    }
+}
+
 pub fn set_controlling_terminal(fd: libc::c_int) -> io::Result<()> {
     unsafe { cvt(libc::ioctl(fd, libc::TIOCSCTTY as _, 0)).map(|_| ()) }
 }
@@ -518,62 +450,36 @@ fn cvt<T: IsMinusOne>(t: T) -> crate::io::Result<T> {
     }
 }
 
+/// A file descriptor that is closed on drop.
 #[derive(Debug)]
 pub struct Fd(RawFd);
 
-impl io::Read for Fd {
-    fn read(&mut self, bytes: &mut [u8]) -> io::Result<usize> {
-        let len =
-            cvt(unsafe { libc::read(self.0, bytes.as_ptr() as *mut libc::c_void, bytes.len()) })?;
+impl Fd {
+    fn read(&self, bytes: &mut [u8]) -> io::Result<usize> {
+        let len = cvt(unsafe {
+            libc::read(self.0, bytes.as_mut_ptr() as *mut libc::c_void, bytes.len())
+        })?;
         Ok(len as usize)
     }
-}
 
-impl io::Write for Fd {
-    fn write(&mut self, bytes: &[u8]) -> io::Result<usize> {
-        let len =
-            cvt(unsafe { libc::write(self.0, bytes.as_ptr() as *mut libc::c_void, bytes.len()) })?;
+    fn write(&self, bytes: &[u8]) -> io::Result<usize> {
+        let len = cvt(unsafe {
+            libc::write(self.0, bytes.as_ptr() as *const libc::c_void, bytes.len())
+        })?;
         Ok(len as usize)
     }
-
-    fn flush(&mut self) -> io::Result<()> {
-        Ok(())
-    }
 }
 
-impl Evented for Fd {
-    fn register(
-        &self,
-        poll: &mio::Poll,
-        token: Token,
-        interest: Ready,
-        opts: PollOpt,
-    ) -> io::Result<()> {
-        EventedFd(&self.0).register(poll, token, interest | UnixReady::hup(), opts)
-    }
-
-    fn reregister(
-        &self,
-        poll: &mio::Poll,
-        token: Token,
-        interest: Ready,
-        opts: PollOpt,
-    ) -> io::Result<()> {
-        EventedFd(&self.0).reregister(poll, token, interest | UnixReady::hup(), opts)
-    }
-
-    fn deregister(&self, poll: &mio::Poll) -> io::Result<()> {
-        EventedFd(&self.0).deregister(poll)
+impl AsRawFd for Fd {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0
     }
 }
 
-type PtyIo = PollEvented<Fd>;
-
-// The Fd in T has to be unique so that tokio can keep track of everything..
-fn stdio(fd: RawFd, handle: &Handle) -> io::Result<Option<PollEvented<Fd>>> {
-    debug!("Creating PollEvented for fd {:?}", fd);
-    // Set the fd to nonblocking before we pass it to the event loop
-    set_nonblock(fd)?;
-    let io = PollEvented::new(Fd(fd), handle)?;
-    Ok(Some(io))
+impl Drop for Fd {
+    fn drop(&mut self) {
+        // Ignore error for same reason as FileDesc in rust stdlib.
+        debug!("Closing fd {}", self.0);
+        let _ = unsafe { libc::close(self.0) };
+    }
 }
@@ -1,14 +1,16 @@
-#[test]
-fn it_works() {}
-
+use std::collections::VecDeque;
 use std::io;
-use std::io::Cursor;
 
-use byteorder::{BigEndian, ReadBytesExt};
-use bytes::{BufMut, BytesMut};
-use tokio_io::codec;
+use bytes::BytesMut;
+use tokio_util::codec;
 
-use crate::parser::{TelnetToken, TelnetTokenizer};
+use crate::carrier::Carrier;
+use crate::demux::{negotiation, TelnetDemuxState};
+use crate::dispatch::DispatchExt;
+use crate::endpoints::{Accept, Naws, NewEnviron, TerminalType};
+use crate::parser::TelnetTokenizer;
+use crate::qstate::QAttitude;
+use crate::registry::{ChannelHandler, EndpointRegistry, TelnetChannel};
 
 #[allow(non_snake_case)]
 pub mod IAC {
@@ -37,151 +39,501 @@ pub mod OPTION {
     // Draft
     pub const LINEMODE: u8 = 34;
     // Proposed
+    pub const TERMINAL_TYPE: u8 = 24;
     pub const NAWS: u8 = 31;
+    pub const NEW_ENVIRON: u8 = 39;
 }
 
-enum TelnetCodecMode {
-    Text,
-    NAWS,
+// Decodes the input by routing it through the demux, which negotiates options, to the endpoints
+// of the registry. Whatever they make of it is collected as `TelnetIn` by `TelnetEvents`.
+pub struct TelnetCodec {
+    tokenizer: TelnetTokenizer,
+    demux: TelnetDemuxState,
+    registry: EndpointRegistry<TelnetEvents>,
+    events: TelnetEvents,
 }
 
-pub struct TelnetCodec {
-    decoder: Decoder,
+impl Default for TelnetCodec {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl TelnetCodec {
     pub fn new() -> TelnetCodec {
-        TelnetCodec {
-            decoder: Decoder::new(),
-        }
+        let mut codec = TelnetCodec {
+            tokenizer: TelnetTokenizer::new(),
+            demux: TelnetDemuxState::new(),
+            registry: EndpointRegistry::new(),
+            events: TelnetEvents::default(),
+        };
+        codec.register(OPTION::NAWS, Box::new(Naws));
+        codec.register(OPTION::TERMINAL_TYPE, Box::new(TerminalType));
+        codec.register(OPTION::NEW_ENVIRON, Box::new(NewEnviron));
+        // The decoder takes care of BINARY, so clients may always ask for it
+        codec.register(OPTION::TRANSMIT_BINARY, Box::new(Accept::both()));
+        codec
+    }
+
+    /// Lets `endpoint` handle an option, its `should_enable` decides what the client may enable.
+    pub fn register(&mut self, option: u8, endpoint: Box<dyn TelnetChannel<TelnetEvents>>) {
+        self.registry.register_channel(option, endpoint);
+    }
+
+    /// Lets `endpoint` handle a command such as BRK.
+    pub fn register_command(
+        &mut self,
+        command: u8,
+        endpoint: Box<dyn TelnetChannel<TelnetEvents>>,
+    ) {
+        self.registry.register_command(command, endpoint);
+    }
+
+    /// Agree to enable `option` on our side (Local) or the client's side (Remote) when asked.
+    /// Requests for options without an endpoint are refused.
+    pub fn support(&mut self, option: u8, attitude: QAttitude) {
+        self.register(option, Box::new(Accept::new(attitude)));
+    }
+
+    pub fn is_enabled(&self, option: u8, attitude: QAttitude) -> bool {
+        self.demux.qstate[option as usize].is_active(attitude)
     }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum TelnetIn {
-    Text { text: Vec<u8> },
+    Text {
+        text: Vec<u8>,
+    },
     Carriage,
-    NAWS { rows: u16, columns: u16 },
+    NAWS {
+        rows: u16,
+        columns: u16,
+    },
+    /// The terminal type as sent by the client, usually in upper case
+    TerminalType {
+        name: String,
+    },
+    /// Environment variables the client sent, undefined ones are left out
+    Environment {
+        variables: Vec<(String, String)>,
+    },
+    /// Answer to the client's negotiation, must be sent back as `TelnetOut::Reply`
+    Reply {
+        data: Vec<u8>,
+    },
+    /// An option was enabled or disabled after negotiation
+    Enabled {
+        option: u8,
+        attitude: QAttitude,
+    },
+    Disabled {
+        option: u8,
+        attitude: QAttitude,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TelnetOut {
+    Text {
+        text: Vec<u8>,
+    },
+    /// Ask for an option to be enabled or disabled, nothing is sent if that is already underway
+    Enable {
+        option: u8,
+        attitude: QAttitude,
+    },
+    Disable {
+        option: u8,
+        attitude: QAttitude,
+    },
+    Reply {
+        data: Vec<u8>,
+    },
+}
+
+// The root of the handlers, endpoints push what they decoded here
+#[derive(Default)]
+pub struct TelnetEvents {
+    pending: VecDeque<TelnetIn>,
+}
+
+impl TelnetEvents {
+    pub fn push(&mut self, item: TelnetIn) {
+        self.pending.push_back(item);
+    }
+}
+
+impl ChannelHandler for TelnetEvents {
+    fn on_data(&mut self, channel: Option<u8>, data: &[u8]) {
+        match channel {
+            None => self.push(TelnetIn::Text {
+                text: data.to_vec(),
+            }),
+            Some(ch) => debug!("Ignoring subnegotiation for option {}", ch),
+        }
+    }
+
+    fn on_command(&mut self, channel: Option<u8>, command: u8) {
+        warn!("unhandled command {:?} {:?}", command, channel);
+    }
+
+    fn on_enable(&mut self, channel: Option<u8>, attitude: QAttitude) {
+        if let Some(option) = channel {
+            self.push(TelnetIn::Enabled { option, attitude });
+        }
+    }
+
+    fn on_disable(&mut self, channel: Option<u8>, attitude: QAttitude) {
+        if let Some(option) = channel {
+            self.push(TelnetIn::Disabled { option, attitude });
+        }
+    }
+
+    fn on_send(&mut self, data: &[u8]) {
+        self.push(TelnetIn::Reply {
+            data: data.to_vec(),
+        });
+    }
 }
-//pub struct TelnetOut;
 
 impl codec::Decoder for TelnetCodec {
     type Item = TelnetIn;
     type Error = io::Error;
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        let len = src.len();
-        if len == 0 {
+        if let Some(item) = self.events.pending.pop_front() {
+            return Ok(Some(item));
+        }
+        if src.is_empty() {
             return Ok(None);
         }
-        let (res, remainder_len) = self.decoder.decode(src.as_ref());
-        debug!("Will drain {} from {}", len - remainder_len, len);
-        src.split_to(len - remainder_len);
-        res
+        let mut endpoints = Carrier {
+            parent: &mut self.events,
+            state: &mut self.registry,
+        };
+        let mut demux = Carrier {
+            parent: &mut endpoints,
+            state: &mut self.demux,
+        };
+        let binary = OPTION::TRANSMIT_BINARY as usize;
+        let mut tokens = self.tokenizer.tokenize(src.as_ref());
+        while let Some(token) = tokens.next() {
+            demux.dispatch(token);
+            // Negotiating BINARY changes how the rest is tokenized
+            tokens.context.binary = demux.state.qstate[binary].remote.is_enabled();
+        }
+        src.clear();
+        Ok(self.events.pending.pop_front())
     }
 }
 
-impl codec::Encoder for TelnetCodec {
-    type Item = Vec<u8>;
+impl codec::Encoder<TelnetOut> for TelnetCodec {
     type Error = io::Error;
 
-    fn encode(&mut self, item: Self::Item, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let len = item.len();
-        if dst.remaining_mut() < len {
-            let increase = ::std::cmp::max(dst.capacity(), len);
-            debug!(
-                "increasing BytesMut with {} from {}",
-                increase,
-                dst.capacity()
-            );
-            dst.reserve(increase);
-        }
-        for c in item {
-            dst.put(c);
+    fn encode(&mut self, item: TelnetOut, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        let (option, attitude, reply) = match item {
+            TelnetOut::Text { text } => {
+                escape(&text, dst);
+                return Ok(());
+            }
+            TelnetOut::Reply { data } => {
+                dst.extend_from_slice(&data);
+                return Ok(());
+            }
+            TelnetOut::Enable { option, attitude } => {
+                let state = self.demux.qstate[option as usize].side(attitude);
+                (option, attitude, state.request_enable())
+            }
+            TelnetOut::Disable { option, attitude } => {
+                let state = self.demux.qstate[option as usize].side(attitude);
+                (option, attitude, state.request_disable())
+            }
+        };
+        if let Some(data) = negotiation(option, attitude, reply) {
+            dst.extend_from_slice(&data);
         }
         Ok(())
     }
 }
 
-struct Decoder {
-    tokenizer: TelnetTokenizer,
-    mode: TelnetCodecMode,
-    server_echo: bool,
+// Doubles 0xFF, so the client doesn't take it for IAC
+fn escape(text: &[u8], dst: &mut BytesMut) {
+    dst.reserve(text.len());
+    for chunk in text.split_inclusive(|&b| b == IAC::IAC) {
+        dst.extend_from_slice(chunk);
+        if chunk.last() == Some(&IAC::IAC) {
+            dst.extend_from_slice(&[IAC::IAC]);
+        }
+    }
 }
 
-impl Decoder {
-    fn new() -> Decoder {
-        Decoder {
-            tokenizer: TelnetTokenizer::new(),
-            mode: TelnetCodecMode::Text,
-            server_echo: false,
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    fn decode_all(codec: &mut TelnetCodec, data: &[u8]) -> Vec<TelnetIn> {
+        let mut buf = BytesMut::from(data);
+        std::iter::from_fn(|| codec.decode(&mut buf).unwrap()).collect()
     }
 
-    fn decode(&mut self, buf: &[u8]) -> (io::Result<Option<TelnetIn>>, usize) {
-        let mut res = Ok(None);
-        let mut stream = self.tokenizer.tokenize(buf);
-        for token in stream.by_ref() {
-            match token {
-                TelnetToken::Text(bytes) => {
-                    match self.mode {
-                        TelnetCodecMode::Text => {
-                            //println!("text {:?} {}", bytes, str::from_utf8(bytes).unwrap_or(""));
-                            res = Ok(Some(TelnetIn::Text {
-                                text: bytes.to_vec(),
-                            }));
-                            break;
-                        }
-                        TelnetCodecMode::NAWS => {
-                            let mut rdr = Cursor::new(bytes);
-                            let cols = From::from(rdr.read_u16::<BigEndian>().unwrap());
-                            let rows = From::from(rdr.read_u16::<BigEndian>().unwrap());
-                            res = Ok(Some(TelnetIn::NAWS {
-                                rows: rows,
-                                columns: cols,
-                            }));
-                            break;
-                        }
-                    }
-                }
-                TelnetToken::Command(command) => match command {
-                    IAC::SE => {
-                        debug!("RX: Negotiation SE");
-                        match self.mode {
-                            TelnetCodecMode::NAWS => {
-                                self.mode = TelnetCodecMode::Text;
-                            }
-                            _ => (),
-                        }
-                    }
-                    command => warn!("unhandled command {:?}", command),
+    fn encode(codec: &mut TelnetCodec, item: TelnetOut) -> Vec<u8> {
+        let mut buf = BytesMut::new();
+        codec.encode(item, &mut buf).unwrap();
+        buf.to_vec()
+    }
+
+    #[test]
+    fn refuses_unknown_options() {
+        let mut codec = TelnetCodec::new();
+        let items = decode_all(
+            &mut codec,
+            &[
+                IAC::IAC,
+                IAC::DO,
+                OPTION::STATUS,
+                IAC::IAC,
+                IAC::WILL,
+                42,
+                b'a',
+            ],
+        );
+        assert_eq!(
+            items,
+            vec![
+                TelnetIn::Reply {
+                    data: vec![IAC::IAC, IAC::WONT, OPTION::STATUS]
+                },
+                TelnetIn::Reply {
+                    data: vec![IAC::IAC, IAC::DONT, 42]
+                },
+                TelnetIn::Text { text: vec![b'a'] },
+            ]
+        );
+    }
+
+    #[test]
+    fn answers_requests_without_looping() {
+        let mut codec = TelnetCodec::new();
+        codec.support(OPTION::ECHO, QAttitude::Local);
+        let enable = TelnetOut::Enable {
+            option: OPTION::ECHO,
+            attitude: QAttitude::Local,
+        };
+        assert_eq!(
+            encode(&mut codec, enable.clone()),
+            vec![IAC::IAC, IAC::WILL, OPTION::ECHO]
+        );
+        // Asking twice sends nothing and the agreement is not answered
+        assert_eq!(encode(&mut codec, enable), vec![]);
+        let items = decode_all(&mut codec, &[IAC::IAC, IAC::DO, OPTION::ECHO]);
+        assert_eq!(
+            items,
+            vec![TelnetIn::Enabled {
+                option: OPTION::ECHO,
+                attitude: QAttitude::Local
+            }]
+        );
+        assert!(codec.is_enabled(OPTION::ECHO, QAttitude::Local));
+
+        let items = decode_all(&mut codec, &[IAC::IAC, IAC::WILL, OPTION::NAWS]);
+        assert_eq!(items.len(), 2);
+        assert_eq!(
+            items[0],
+            TelnetIn::Reply {
+                data: vec![IAC::IAC, IAC::DO, OPTION::NAWS]
+            }
+        );
+        let items = decode_all(
+            &mut codec,
+            &[
+                IAC::IAC,
+                IAC::SB,
+                OPTION::NAWS,
+                0,
+                80,
+                0,
+                24,
+                IAC::IAC,
+                IAC::SE,
+            ],
+        );
+        assert_eq!(
+            items,
+            vec![TelnetIn::NAWS {
+                rows: 24,
+                columns: 80
+            }]
+        );
+        let items = decode_all(&mut codec, &[IAC::IAC, IAC::WONT, OPTION::NAWS]);
+        assert_eq!(
+            items,
+            vec![
+                TelnetIn::Reply {
+                    data: vec![IAC::IAC, IAC::DONT, OPTION::NAWS]
                 },
-                TelnetToken::Negotiation { command, channel } => match (command, channel) {
-                    (IAC::DO, OPTION::ECHO) => {
-                        debug!("RX: Negotiation DO ECHO");
-                        self.server_echo = true
-                    }
-                    (IAC::DONT, OPTION::ECHO) => {
-                        debug!("RX: Negotiation DONT ECHO");
-                        self.server_echo = false
-                    }
-                    (IAC::DO, OPTION::SUPPRESS_GO_AHEAD) => {
-                        debug!("RX: Negotiation DO SUPPRESS_GO_AHEAD");
-                    }
-                    (IAC::DONT, OPTION::SUPPRESS_GO_AHEAD) => {
-                        debug!("RX: Negotiation DONT SUPPRESS_GO_AHEAD");
-                    }
-                    (IAC::WILL, OPTION::NAWS) => {
-                        debug!("RX: Negotiation WILL NAWS");
-                    }
-                    (IAC::SB, OPTION::NAWS) => {
-                        debug!("RX: Negotiation SB NAWS");
-                        self.mode = TelnetCodecMode::NAWS;
-                    }
-                    (_, _) => warn!("unhandled negotiation {:?} {:?}", command, channel),
+                TelnetIn::Disabled {
+                    option: OPTION::NAWS,
+                    attitude: QAttitude::Remote
                 },
+            ]
+        );
+    }
+
+    #[test]
+    fn passes_cr_in_binary_mode() {
+        let mut codec = TelnetCodec::new();
+        let mut data = b"a\r\0b".to_vec();
+        data.extend(&[IAC::IAC, IAC::WILL, OPTION::TRANSMIT_BINARY]);
+        data.extend(b"a\r\0b");
+        let items = decode_all(&mut codec, &data);
+        assert_eq!(
+            items[4],
+            TelnetIn::Enabled {
+                option: OPTION::TRANSMIT_BINARY,
+                attitude: QAttitude::Remote
             }
+        );
+        let text = |text: &[u8]| TelnetIn::Text {
+            text: text.to_vec(),
+        };
+        assert_eq!(items[..3], [text(b"a"), text(b"\r"), text(b"b")]);
+        assert_eq!(items[5..], [text(b"a\r\0b")]);
+    }
+
+    fn naws(rows: u16, columns: u16) -> TelnetIn {
+        TelnetIn::NAWS { rows, columns }
+    }
+
+    #[test]
+    fn buffers_split_subnegotiation() {
+        let mut codec = TelnetCodec::new();
+        let mut items = Vec::new();
+        // Width 255 is escaped, a height of 13 is CR and must not be taken for a line end
+        for &byte in &[
+            IAC::IAC,
+            IAC::SB,
+            OPTION::NAWS,
+            0,
+            0xFF,
+            0xFF,
+            0,
+            13,
+            IAC::IAC,
+            IAC::SE,
+            b'x',
+        ] {
+            items.extend(decode_all(&mut codec, &[byte]));
         }
-        (res, stream.data.len())
+        assert_eq!(
+            items,
+            vec![naws(13, 255), TelnetIn::Text { text: vec![b'x'] }]
+        );
+    }
+
+    #[test]
+    fn discards_bad_subnegotiation() {
+        let mut codec = TelnetCodec::new();
+        // Too short, too long and cut off by another SB
+        let mut data = vec![IAC::IAC, IAC::SB, OPTION::NAWS, 1, IAC::IAC, IAC::SE];
+        data.extend(&[IAC::IAC, IAC::SB, OPTION::NAWS]);
+        data.extend(vec![0; crate::demux::MAX_SUBNEGOTIATION + 1]);
+        data.extend(&[IAC::IAC, IAC::SE, IAC::IAC, IAC::SB, OPTION::NAWS, 0, 1]);
+        data.extend(&[
+            IAC::IAC,
+            IAC::SB,
+            OPTION::NAWS,
+            0,
+            80,
+            0,
+            24,
+            IAC::IAC,
+            IAC::SE,
+        ]);
+        data.extend(b"ok");
+        let items = decode_all(&mut codec, &data);
+        assert_eq!(
+            items,
+            vec![
+                naws(24, 80),
+                TelnetIn::Text {
+                    text: b"ok".to_vec()
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn escapes_iac_in_text() {
+        let mut codec = TelnetCodec::new();
+        let text = vec![b'a', 0xFF, 0xFF, b'b'];
+        assert_eq!(
+            encode(&mut codec, TelnetOut::Text { text }),
+            vec![b'a', 0xFF, 0xFF, 0xFF, 0xFF, b'b']
+        );
+    }
+
+    #[test]
+    fn asks_for_terminal_type() {
+        let mut codec = TelnetCodec::new();
+        let items = decode_all(&mut codec, &[IAC::IAC, IAC::WILL, OPTION::TERMINAL_TYPE]);
+        let send = vec![
+            IAC::IAC,
+            IAC::SB,
+            OPTION::TERMINAL_TYPE,
+            1,
+            IAC::IAC,
+            IAC::SE,
+        ];
+        assert_eq!(items[2], TelnetIn::Reply { data: send });
+        let mut data = vec![IAC::IAC, IAC::SB, OPTION::TERMINAL_TYPE, 0];
+        data.extend(b"XTERM-256COLOR");
+        data.extend(&[IAC::IAC, IAC::SE]);
+        // Not printable
+        data.extend(&[
+            IAC::IAC,
+            IAC::SB,
+            OPTION::TERMINAL_TYPE,
+            0,
+            b'x',
+            27,
+            IAC::IAC,
+            IAC::SE,
+        ]);
+        let items = decode_all(&mut codec, &data);
+        assert_eq!(
+            items,
+            vec![TelnetIn::TerminalType {
+                name: "XTERM-256COLOR".to_owned()
+            }]
+        );
+    }
+
+    #[test]
+    fn parses_environment() {
+        let mut codec = TelnetCodec::new();
+        let mut data = vec![IAC::IAC, IAC::SB, OPTION::NEW_ENVIRON, 0];
+        // USER=joe, an undefined DISPLAY and a user variable with escaped bytes and an empty value
+        data.extend(b"\x00USER\x01joe\x00DISPLAY\x03A\x02\x01B\x01\x03EMPTY\x01");
+        data.extend(&[IAC::IAC, IAC::SE]);
+        // A value without a variable
+        data.extend(&[
+            IAC::IAC,
+            IAC::SB,
+            OPTION::NEW_ENVIRON,
+            0,
+            1,
+            b'x',
+            IAC::IAC,
+            IAC::SE,
+        ]);
+        let items = decode_all(&mut codec, &data);
+        let variables = vec![
+            ("USER".to_owned(), "joe".to_owned()),
+            ("A\x01B".to_owned(), "".to_owned()),
+            ("EMPTY".to_owned(), "".to_owned()),
+        ];
+        assert_eq!(items, vec![TelnetIn::Environment { variables }]);
     }
 }
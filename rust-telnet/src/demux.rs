@@ -1,34 +1,103 @@
+use std::mem;
+
 use crate::carrier::Carrier;
 use crate::dispatch::DispatchHandler;
 use crate::iac::IAC;
-use crate::qstate::{QAttitude, QState};
+use crate::qstate::{QAttitude, QReply, QState};
 
 pub trait ChannelHandler {
-    fn on_data<'a>(&mut self, _channel: Option<u8>, _data: &'a [u8]) {}
+    fn on_data(&mut self, _channel: Option<u8>, _data: &[u8]) {}
     fn on_command(&mut self, _channel: Option<u8>, _command: u8) {}
 
-    fn on_enable(&mut self, _channel: Option<u8>) {}
-    fn on_disable(&mut self, _channel: Option<u8>) {}
+    fn on_enable(&mut self, _channel: Option<u8>, _attitude: QAttitude) {}
+    fn on_disable(&mut self, _channel: Option<u8>, _attitude: QAttitude) {}
     fn on_focus(&mut self, _channel: Option<u8>) {}
     fn on_blur(&mut self, _channel: Option<u8>) {}
 
     fn should_enable(&mut self, _channel: Option<u8>, _attitude: QAttitude) -> bool {
         false
     }
+
+    // Bytes that have to be sent to the other side, such as answers to negotiation
+    fn on_send(&mut self, _data: &[u8]) {}
 }
 
 impl ChannelHandler for () {}
 
+// Longest subnegotiation payload that is accepted, anything longer is discarded
+pub const MAX_SUBNEGOTIATION: usize = 4096;
+
 #[derive(Clone)]
 pub struct TelnetDemuxState {
     pub qstate: Vec<QState>,
     pub active_channel: Option<u8>,
+    // Payload of the current subnegotiation, handed on in one piece at IAC SE
+    pub buffer: Vec<u8>,
+    pub overflow: bool,
+}
+impl Default for TelnetDemuxState {
+    fn default() -> Self {
+        Self::new()
+    }
 }
+
 impl TelnetDemuxState {
     pub fn new() -> TelnetDemuxState {
         TelnetDemuxState {
             qstate: vec![QState::new(); 256],
             active_channel: None,
+            buffer: Vec::new(),
+            overflow: false,
+        }
+    }
+}
+
+// The bytes to send for a Q method reply about our (Local) or the other (Remote) side
+pub fn negotiation(channel: u8, attitude: QAttitude, reply: QReply) -> Option<Vec<u8>> {
+    let command = match (attitude, reply?) {
+        (QAttitude::Local, true) => IAC::WILL,
+        (QAttitude::Local, false) => IAC::WONT,
+        (QAttitude::Remote, true) => IAC::DO,
+        (QAttitude::Remote, false) => IAC::DONT,
+    };
+    debug!("TX: Negotiation {} {}", command, channel);
+    Some(vec![IAC::IAC, command, channel])
+}
+
+impl<'state, 'parent, Parent> Carrier<'state, 'parent, Parent, TelnetDemuxState>
+where
+    Parent: ChannelHandler,
+{
+    // Runs the Q method of RFC 1143 for a WILL, WONT, DO or DONT from the other side
+    fn negotiate(&mut self, channel: u8, command: u8) {
+        debug!("RX: Negotiation {} {}", command, channel);
+        let attitude = match command {
+            IAC::WILL | IAC::WONT => QAttitude::Remote,
+            _ => QAttitude::Local,
+        };
+        let enable = command == IAC::WILL || command == IAC::DO;
+        let state = self.state.qstate[channel as usize];
+        // Only a disabled option needs the approval of the handler
+        let agree = enable
+            && !state.is_active(attitude)
+            && self.parent.should_enable(Some(channel), attitude);
+
+        let state = &mut self.state.qstate[channel as usize];
+        let was_enabled = state.is_active(attitude);
+        let reply = if enable {
+            state.side(attitude).receive_enable(agree)
+        } else {
+            state.side(attitude).receive_disable()
+        };
+        let is_enabled = state.is_active(attitude);
+
+        if let Some(data) = negotiation(channel, attitude, reply) {
+            self.parent.on_send(&data);
+        }
+        if is_enabled && !was_enabled {
+            self.parent.on_enable(Some(channel), attitude);
+        } else if was_enabled && !is_enabled {
+            self.parent.on_disable(Some(channel), attitude);
         }
     }
 }
@@ -37,25 +106,47 @@ impl<'state, 'parent, Parent> DispatchHandler for Carrier<'state, 'parent, Paren
 where
     Parent: ChannelHandler,
 {
-    fn on_data<'a>(&mut self, data: &'a [u8]) {
-        self.parent.on_data(self.state.active_channel, data);
+    fn on_data(&mut self, data: &[u8]) {
+        let channel = match self.state.active_channel {
+            None => return self.parent.on_data(None, data),
+            Some(ch) => ch,
+        };
+        if self.state.overflow {
+            return;
+        }
+        if self.state.buffer.len() + data.len() > MAX_SUBNEGOTIATION {
+            warn!(
+                "Discarding subnegotiation for option {} longer than {} bytes",
+                channel, MAX_SUBNEGOTIATION
+            );
+            self.state.buffer = Vec::new();
+            self.state.overflow = true;
+            return;
+        }
+        self.state.buffer.extend_from_slice(data);
     }
 
     fn on_command(&mut self, channel: Option<u8>, command: u8) {
         match channel {
             None => match command {
                 IAC::SE => {
-                    self.parent.on_blur(self.state.active_channel);
+                    let active_channel = self.state.active_channel;
+                    let data = mem::take(&mut self.state.buffer);
+                    if active_channel.is_some() && !self.state.overflow {
+                        self.parent.on_data(active_channel, &data);
+                    }
+                    self.state.overflow = false;
+                    self.parent.on_blur(active_channel);
                     self.state.active_channel = channel;
                 }
                 _ => self.parent.on_command(channel, command),
             },
             Some(ch) => match command {
-                IAC::WILL => println!("IAC WILL {}", ch),
-                IAC::WONT => println!("IAC WONT {}", ch),
-                IAC::DO => println!("IAC DO {}", ch),
-                IAC::DONT => println!("IAC DONT {}", ch),
+                IAC::WILL | IAC::WONT | IAC::DO | IAC::DONT => self.negotiate(ch, command),
                 IAC::SB => {
+                    // A subnegotiation that never ended is dropped
+                    self.state.buffer.clear();
+                    self.state.overflow = false;
                     self.parent.on_focus(channel);
                     self.state.active_channel = channel;
                 }
@@ -1,7 +1,7 @@
 use crate::parser::TelnetToken;
 
 pub trait DispatchHandler {
-    fn on_data<'a>(&mut self, _data: &'a [u8]) {}
+    fn on_data(&mut self, _data: &[u8]) {}
     fn on_command(&mut self, _channel: Option<u8>, _command: u8) {}
 }
 impl DispatchHandler for () {}
@@ -0,0 +1,170 @@
+use std::io::Cursor;
+
+use byteorder::{BigEndian, ReadBytesExt};
+
+use crate::codec::{TelnetEvents, TelnetIn};
+use crate::iac::IAC;
+use crate::qstate::QAttitude;
+use crate::registry::{ChannelHandler, TelnetChannel};
+
+// Agrees to enable an option on our side, the client's side or both, for options without data
+// of their own such as ECHO.
+pub struct Accept {
+    pub local: bool,
+    pub remote: bool,
+}
+
+impl Accept {
+    pub fn new(attitude: QAttitude) -> Accept {
+        Accept {
+            local: attitude == QAttitude::Local,
+            remote: attitude == QAttitude::Remote,
+        }
+    }
+
+    pub fn both() -> Accept {
+        Accept {
+            local: true,
+            remote: true,
+        }
+    }
+}
+
+impl<Parent> TelnetChannel<Parent> for Accept
+where
+    Parent: ChannelHandler,
+{
+    fn should_enable(&mut self, _: &mut Parent, _: Option<u8>, attitude: QAttitude) -> bool {
+        match attitude {
+            QAttitude::Local => self.local,
+            QAttitude::Remote => self.remote,
+        }
+    }
+}
+
+// Window size reports of RFC 1073, the client enables NAWS on its side.
+pub struct Naws;
+
+impl TelnetChannel<TelnetEvents> for Naws {
+    fn on_data(&mut self, parent: &mut TelnetEvents, _: Option<u8>, data: &[u8]) {
+        if data.len() < 4 {
+            warn!("Ignoring short NAWS subnegotiation {:?}", data);
+            return;
+        }
+        let mut rdr = Cursor::new(data);
+        let columns = rdr.read_u16::<BigEndian>().unwrap();
+        let rows = rdr.read_u16::<BigEndian>().unwrap();
+        parent.push(TelnetIn::NAWS { rows, columns });
+    }
+
+    fn should_enable(&mut self, _: &mut TelnetEvents, _: Option<u8>, attitude: QAttitude) -> bool {
+        attitude == QAttitude::Remote
+    }
+}
+
+// Subnegotiation commands of TERMINAL-TYPE and NEW-ENVIRON
+const IS: u8 = 0;
+const SEND: u8 = 1;
+const INFO: u8 = 2;
+
+// Asks for `option` with IAC SB option SEND IAC SE
+fn send_request(parent: &mut TelnetEvents, option: u8) {
+    parent.on_send(&[IAC::IAC, IAC::SB, option, SEND, IAC::IAC, IAC::SE]);
+}
+
+// Terminal type of RFC 1091, asked for as soon as the client enables the option.
+pub struct TerminalType;
+
+// Longest terminal type allowed by RFC 1091
+const MAX_TERMINAL_TYPE: usize = 40;
+
+impl TelnetChannel<TelnetEvents> for TerminalType {
+    fn on_data(&mut self, parent: &mut TelnetEvents, _: Option<u8>, data: &[u8]) {
+        match data.split_first() {
+            Some((&IS, name))
+                if !name.is_empty()
+                    && name.len() <= MAX_TERMINAL_TYPE
+                    && name.iter().all(u8::is_ascii_graphic) =>
+            {
+                let name = String::from_utf8_lossy(name).into_owned();
+                parent.push(TelnetIn::TerminalType { name });
+            }
+            _ => warn!("Ignoring invalid terminal type {:?}", data),
+        }
+    }
+
+    fn on_enable(&mut self, parent: &mut TelnetEvents, channel: Option<u8>, attitude: QAttitude) {
+        parent.on_enable(channel, attitude);
+        if let (Some(option), QAttitude::Remote) = (channel, attitude) {
+            send_request(parent, option);
+        }
+    }
+
+    fn should_enable(&mut self, _: &mut TelnetEvents, _: Option<u8>, attitude: QAttitude) -> bool {
+        attitude == QAttitude::Remote
+    }
+}
+
+// Environment variables of RFC 1572. All of them are asked for when the client enables the
+// option, it may send updates later.
+pub struct NewEnviron;
+
+const VAR: u8 = 0;
+const VALUE: u8 = 1;
+const ESC: u8 = 2;
+const USERVAR: u8 = 3;
+
+impl TelnetChannel<TelnetEvents> for NewEnviron {
+    fn on_data(&mut self, parent: &mut TelnetEvents, _: Option<u8>, data: &[u8]) {
+        let variables = match data.split_first() {
+            Some((&IS, list)) | Some((&INFO, list)) => parse_environ(list),
+            _ => None,
+        };
+        match variables {
+            Some(variables) => parent.push(TelnetIn::Environment { variables }),
+            None => warn!("Ignoring invalid environment {:?}", data),
+        }
+    }
+
+    fn on_enable(&mut self, parent: &mut TelnetEvents, channel: Option<u8>, attitude: QAttitude) {
+        parent.on_enable(channel, attitude);
+        if let (Some(option), QAttitude::Remote) = (channel, attitude) {
+            send_request(parent, option);
+        }
+    }
+
+    fn should_enable(&mut self, _: &mut TelnetEvents, _: Option<u8>, attitude: QAttitude) -> bool {
+        attitude == QAttitude::Remote
+    }
+}
+
+// Parses a list of VAR or USERVAR name, optionally followed by VALUE value. Returns None if it
+// doesn't start with a variable or ends in the middle of an escape.
+fn parse_environ(list: &[u8]) -> Option<Vec<(String, String)>> {
+    let mut variables = Vec::new();
+    let mut current: Option<(Vec<u8>, Option<Vec<u8>>)> = None;
+    let mut bytes = list.iter();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            VAR | USERVAR => {
+                variables.extend(current.take().and_then(defined));
+                current = Some((Vec::new(), None));
+            }
+            VALUE => current.as_mut()?.1 = Some(Vec::new()),
+            byte => {
+                let byte = if byte == ESC { *bytes.next()? } else { byte };
+                match current.as_mut()? {
+                    (_, Some(value)) => value.push(byte),
+                    (name, None) => name.push(byte),
+                }
+            }
+        }
+    }
+    variables.extend(current.and_then(defined));
+    Some(variables)
+}
+
+fn defined((name, value): (Vec<u8>, Option<Vec<u8>>)) -> Option<(String, String)> {
+    let string = |bytes: Vec<u8>| String::from_utf8_lossy(&bytes).into_owned();
+    Some((string(name), string(value?)))
+}
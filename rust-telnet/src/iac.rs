@@ -6,4 +6,5 @@ pub mod IAC {
     pub const WONT: u8 = 252;
     pub const DO: u8 = 253;
     pub const DONT: u8 = 254;
+    pub const IAC: u8 = 255;
 }
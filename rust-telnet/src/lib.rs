@@ -6,6 +6,7 @@ pub mod carrier;
 pub mod codec;
 pub mod demux;
 pub mod dispatch;
+pub mod endpoints;
 pub mod parser;
 pub mod registry;
 
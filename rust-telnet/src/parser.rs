@@ -1,4 +1,5 @@
 use self::ParseState::*;
+use crate::iac::IAC;
 
 #[derive(Copy, Clone, Debug)]
 pub enum ParseState {
@@ -19,24 +20,36 @@ pub enum TelnetToken<'a> {
 //  tokenizer.is_long_command = (box |&: _: u8| false) as Box<Fn(u8) -> bool>;
 pub struct TelnetTokenizer {
     pub state: ParseState,
+    // Between IAC SB and IAC SE the data is binary, CR has no special meaning
+    pub subnegotiation: bool,
+    // Set while the other side transmits binary (RFC 856), CR is plain data then as well
+    pub binary: bool,
 
     // TODO: Once types of unboxed closures can be written,
     //   replace this with a trait Pred: Fn(u8) -> bool.
     pub is_long_command: Box<dyn Fn(u8) -> bool + 'static>,
 }
 
+impl Default for TelnetTokenizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl TelnetTokenizer {
     pub fn new() -> TelnetTokenizer {
         TelnetTokenizer {
             state: ParseState::Neutral,
-            is_long_command: Box::new(|cmd| 250 <= cmd && cmd <= 254),
+            subnegotiation: false,
+            binary: false,
+            is_long_command: Box::new(|cmd| (250..=254).contains(&cmd)),
         }
     }
 
     pub fn tokenize<'a, 'b>(&'a mut self, data: &'b [u8]) -> TokenStream<'a, 'b> {
         TokenStream {
             context: self,
-            data: data,
+            data,
         }
     }
 }
@@ -56,16 +69,19 @@ impl<'a, 'b> TokenStream<'a, 'b> {
             (Some(TelnetToken::Text(b"\r")), Neutral, &self.data[1..])
         } else {
             // invalid stream, technically, but still unambiguous
-            (Some(TelnetToken::Text(b"\r")), Neutral, &self.data)
+            (Some(TelnetToken::Text(b"\r")), Neutral, self.data)
         }
     }
 
-    fn command_state(&self) -> ParseResult<'b> {
+    fn command_state(&mut self) -> ParseResult<'b> {
         if (*self.context.is_long_command)(self.data[0]) {
             (None, Subchannel(self.data[0]), &self.data[1..])
         } else if self.data[0] == b'\xFF' {
             (Some(TelnetToken::Text(b"\xFF")), Neutral, &self.data[1..])
         } else {
+            if self.data[0] == IAC::SE {
+                self.context.subnegotiation = false;
+            }
             (
                 Some(TelnetToken::Command(self.data[0])),
                 Neutral,
@@ -74,10 +90,13 @@ impl<'a, 'b> TokenStream<'a, 'b> {
         }
     }
 
-    fn subchannel_state(&self, command: u8) -> ParseResult<'b> {
+    fn subchannel_state(&mut self, command: u8) -> ParseResult<'b> {
+        if command == IAC::SB {
+            self.context.subnegotiation = true;
+        }
         (
             Some(TelnetToken::Negotiation {
-                command: command,
+                command,
                 channel: self.data[0],
             }),
             Neutral,
@@ -86,10 +105,11 @@ impl<'a, 'b> TokenStream<'a, 'b> {
     }
 
     fn neutral_state(&self) -> ParseResult<'b> {
+        let binary = self.context.subnegotiation || self.context.binary;
         let maybe_idx = self
             .data
             .iter()
-            .position(|ch: &u8| -> bool { *ch == b'\r' || *ch == b'\xFF' });
+            .position(|ch: &u8| -> bool { (*ch == b'\r' && !binary) || *ch == b'\xFF' });
 
         match maybe_idx {
             Some(idx) => {
@@ -128,7 +148,7 @@ impl<'a, 'b> TokenStream<'a, 'b> {
 
         self.context.state = state;
         self.data = data;
-        return token;
+        token
     }
 }
 
@@ -143,6 +163,6 @@ impl<'a, 'b> Iterator for TokenStream<'a, 'b> {
             }
         }
 
-        return None;
+        None
     }
 }
@@ -1,24 +1,91 @@
 #![allow(dead_code)]
 
+// One side of an option as tracked by the Q method of RFC 1143. The `Opposite` states have a
+// request to change the option back queued until the pending answer arrives.
 #[derive(Debug, Copy, Clone, PartialEq)]
 pub enum QStateUni {
     Disabled,
     AwaitEnable,
+    AwaitEnableOpposite,
     Enabled,
     AwaitDisable,
+    AwaitDisableOpposite,
 }
 
+// What to answer, Some(true) for DO/WILL, Some(false) for DONT/WONT, None for nothing
+pub type QReply = Option<bool>;
+
 impl QStateUni {
     pub fn is_enabled(self) -> bool {
-        self == QStateUni::Enabled || self == QStateUni::AwaitDisable
+        matches!(
+            self,
+            QStateUni::Enabled | QStateUni::AwaitDisable | QStateUni::AwaitDisableOpposite
+        )
     }
 
     pub fn is_disabled(self) -> bool {
         !self.is_enabled()
     }
+
+    /// The other side sent WILL (or DO), `agree` tells whether we allow the option.
+    pub fn receive_enable(&mut self, agree: bool) -> QReply {
+        use self::QStateUni::*;
+        let (state, reply) = match *self {
+            Disabled if agree => (Enabled, Some(true)),
+            Disabled => (Disabled, Some(false)),
+            Enabled => (Enabled, None),
+            // The other side answered our refusal with an agreement, it is confused
+            AwaitDisable => (Disabled, None),
+            AwaitDisableOpposite => (Enabled, None),
+            AwaitEnable => (Enabled, None),
+            AwaitEnableOpposite => (AwaitDisable, Some(false)),
+        };
+        *self = state;
+        reply
+    }
+
+    /// The other side sent WONT (or DONT).
+    pub fn receive_disable(&mut self) -> QReply {
+        use self::QStateUni::*;
+        let (state, reply) = match *self {
+            Disabled => (Disabled, None),
+            Enabled => (Disabled, Some(false)),
+            AwaitDisable => (Disabled, None),
+            AwaitDisableOpposite => (AwaitEnable, Some(true)),
+            AwaitEnable | AwaitEnableOpposite => (Disabled, None),
+        };
+        *self = state;
+        reply
+    }
+
+    /// We want the option enabled.
+    pub fn request_enable(&mut self) -> QReply {
+        use self::QStateUni::*;
+        let (state, reply) = match *self {
+            Disabled => (AwaitEnable, Some(true)),
+            AwaitDisable => (AwaitDisableOpposite, None),
+            AwaitEnableOpposite => (AwaitEnable, None),
+            state => (state, None),
+        };
+        *self = state;
+        reply
+    }
+
+    /// We want the option disabled.
+    pub fn request_disable(&mut self) -> QReply {
+        use self::QStateUni::*;
+        let (state, reply) = match *self {
+            Enabled => (AwaitDisable, Some(false)),
+            AwaitEnable => (AwaitEnableOpposite, None),
+            AwaitDisableOpposite => (AwaitDisable, None),
+            state => (state, None),
+        };
+        *self = state;
+        reply
+    }
 }
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, PartialEq)]
 pub enum QAttitude {
     Local,
     Remote,
@@ -30,6 +97,12 @@ pub struct QState {
     pub remote: QStateUni,
 }
 
+impl Default for QState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl QState {
     pub fn new() -> QState {
         QState {
@@ -44,4 +117,43 @@ impl QState {
             QAttitude::Remote => self.remote.is_enabled(),
         }
     }
+
+    pub fn side(&mut self, attitude: QAttitude) -> &mut QStateUni {
+        match attitude {
+            QAttitude::Local => &mut self.local,
+            QAttitude::Remote => &mut self.remote,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::QStateUni::*;
+
+    #[test]
+    fn answers_requests_once() {
+        let mut state = Disabled;
+        assert_eq!(state.receive_enable(true), Some(true));
+        assert_eq!(state, Enabled);
+        // A repeated request is not answered again, that would loop
+        assert_eq!(state.receive_enable(true), None);
+        assert_eq!(state.receive_disable(), Some(false));
+        assert_eq!(state.receive_disable(), None);
+        assert_eq!(state.receive_enable(false), Some(false));
+        assert_eq!(state, Disabled);
+    }
+
+    #[test]
+    fn queues_the_opposite_request() {
+        let mut state = Disabled;
+        assert_eq!(state.request_enable(), Some(true));
+        // Changing our mind is only sent once the answer arrived
+        assert_eq!(state.request_disable(), None);
+        assert_eq!(state, AwaitEnableOpposite);
+        assert_eq!(state.receive_enable(true), Some(false));
+        assert_eq!(state, AwaitDisable);
+        assert!(state.is_enabled());
+        assert_eq!(state.receive_disable(), None);
+        assert_eq!(state, Disabled);
+    }
 }
@@ -4,253 +4,225 @@ use crate::qstate::QAttitude;
 use std::collections::HashMap;
 use std::vec::Vec;
 
-pub trait TelnetChannel<Parent> {
-    fn on_data<'a>(&mut self, _parent: &mut Parent, _channel: Option<u8>, _data: &'a [u8]) {}
-    fn on_command(&mut self, _parent: &mut Parent, _channel: Option<u8>, _command: u8) {}
-
-    fn on_enable(&mut self, _parent: &mut Parent, _channel: Option<u8>) {}
-    fn on_disable(&mut self, _parent: &mut Parent, _channel: Option<u8>) {}
-    fn on_focus(&mut self, _parent: &mut Parent, _channel: Option<u8>) {}
-    fn on_blur(&mut self, _parent: &mut Parent, _channel: Option<u8>) {}
-
-    fn should_enable(
-        &mut self,
-        _parent: &mut Parent,
-        _channel: Option<u8>,
-        _attitude: QAttitude,
-    ) -> bool {
-        false
-    }
-}
-
-impl<Parent> TelnetChannel<Parent> for ()
-where
-    Parent: ChannelHandler,
-{
-    fn on_data<'a>(&mut self, parent: &mut Parent, channel: Option<u8>, text: &'a [u8]) {
-        Carrier {
-            parent: parent,
-            state: self,
-        }
-        .on_data(channel, text)
+// An endpoint handles one or more channels (options) or commands. Everything it doesn't handle
+// itself is passed on to the parent.
+pub trait TelnetChannel<Parent: ChannelHandler> {
+    fn on_data(&mut self, parent: &mut Parent, channel: Option<u8>, data: &[u8]) {
+        parent.on_data(channel, data)
     }
     fn on_command(&mut self, parent: &mut Parent, channel: Option<u8>, command: u8) {
-        Carrier {
-            parent: parent,
-            state: self,
-        }
-        .on_command(channel, command)
+        parent.on_command(channel, command)
     }
-    fn on_enable(&mut self, parent: &mut Parent, channel: Option<u8>) {
-        Carrier {
-            parent: parent,
-            state: self,
-        }
-        .on_enable(channel)
+
+    fn on_enable(&mut self, parent: &mut Parent, channel: Option<u8>, attitude: QAttitude) {
+        parent.on_enable(channel, attitude)
     }
-    fn on_disable(&mut self, parent: &mut Parent, channel: Option<u8>) {
-        Carrier {
-            parent: parent,
-            state: self,
-        }
-        .on_disable(channel)
+    fn on_disable(&mut self, parent: &mut Parent, channel: Option<u8>, attitude: QAttitude) {
+        parent.on_disable(channel, attitude)
     }
     fn on_focus(&mut self, parent: &mut Parent, channel: Option<u8>) {
-        Carrier {
-            parent: parent,
-            state: self,
-        }
-        .on_focus(channel)
+        parent.on_focus(channel)
     }
     fn on_blur(&mut self, parent: &mut Parent, channel: Option<u8>) {
-        Carrier {
-            parent: parent,
-            state: self,
-        }
-        .on_focus(channel)
+        parent.on_blur(channel)
     }
+
     fn should_enable(
         &mut self,
         parent: &mut Parent,
         channel: Option<u8>,
         attitude: QAttitude,
     ) -> bool {
-        Carrier {
-            parent: parent,
-            state: self,
-        }
-        .should_enable(channel, attitude)
+        parent.should_enable(channel, attitude)
     }
 }
-impl<'parent, 'state, Parent> ChannelHandler for Carrier<'parent, 'state, Parent, ()>
-where
-    Parent: ChannelHandler,
-{
-    fn on_data<'a>(&mut self, channel: Option<u8>, data: &'a [u8]) {
-        self.parent.on_data(channel, data)
-    }
-    fn on_command(&mut self, channel: Option<u8>, command: u8) {
-        self.parent.on_command(channel, command)
-    }
 
-    fn on_enable(&mut self, channel: Option<u8>) {
-        self.parent.on_enable(channel)
-    }
-    fn on_disable(&mut self, channel: Option<u8>) {
-        self.parent.on_disable(channel)
-    }
-    fn on_focus(&mut self, channel: Option<u8>) {
-        self.parent.on_focus(channel)
-    }
-    fn on_blur(&mut self, channel: Option<u8>) {
-        self.parent.on_blur(channel)
-    }
-
-    fn should_enable(&mut self, channel: Option<u8>, attitude: QAttitude) -> bool {
-        self.parent.should_enable(channel, attitude)
-    }
-}
-
-// Routes command and channel events to the appropriate handlers.
-pub struct EndpointRegistry<'parent, Parent: 'parent> {
-    pub parent: &'parent mut Parent,
+impl<Parent> TelnetChannel<Parent> for () where Parent: ChannelHandler {}
 
+// Routes command and channel events to the appropriate handlers. Use it as the state of a
+// `Carrier` to get a `ChannelHandler`.
+pub struct EndpointRegistry<Parent: ChannelHandler> {
     pub command_map: HashMap<u8, usize>,
     pub channel_map: HashMap<u8, usize>,
-    pub endpoints: Vec<&'parent mut (dyn TelnetChannel<Parent> + 'parent)>,
+    pub endpoints: Vec<Box<dyn TelnetChannel<Parent>>>,
 
-    pub main: Option<&'parent mut (dyn TelnetChannel<Parent> + 'parent)>,
+    pub main: Option<Box<dyn TelnetChannel<Parent>>>,
 }
-impl<'parent, Parent> EndpointRegistry<'parent, Parent>
+
+impl<Parent> Default for EndpointRegistry<Parent>
 where
     Parent: ChannelHandler,
 {
-    pub fn new(parent: &'parent mut Parent) -> EndpointRegistry<'parent, Parent> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<Parent> EndpointRegistry<Parent>
+where
+    Parent: ChannelHandler,
+{
+    pub fn new() -> EndpointRegistry<Parent> {
         EndpointRegistry {
             command_map: HashMap::new(),
             channel_map: HashMap::new(),
             endpoints: Vec::new(),
 
             main: None,
-            parent: parent,
         }
     }
+
+    /// Lets `endpoint` handle an option, replacing the previous endpoint for it.
+    pub fn register_channel(&mut self, channel: u8, endpoint: Box<dyn TelnetChannel<Parent>>) {
+        let id = self.add_endpoint(self.channel_map.get(&channel).cloned(), endpoint);
+        self.channel_map.insert(channel, id);
+    }
+
+    /// Lets `endpoint` handle a command such as IAC BRK, replacing the previous endpoint for it.
+    pub fn register_command(&mut self, command: u8, endpoint: Box<dyn TelnetChannel<Parent>>) {
+        let id = self.add_endpoint(self.command_map.get(&command).cloned(), endpoint);
+        self.command_map.insert(command, id);
+    }
+
+    // Stores `endpoint` in place of the one at `id`, or as a new one
+    fn add_endpoint(
+        &mut self,
+        id: Option<usize>,
+        endpoint: Box<dyn TelnetChannel<Parent>>,
+    ) -> usize {
+        match id {
+            Some(id) => {
+                self.endpoints[id] = endpoint;
+                id
+            }
+            None => {
+                self.endpoints.push(endpoint);
+                self.endpoints.len() - 1
+            }
+        }
+    }
+
+    fn endpoint(
+        &mut self,
+        channel: Option<u8>,
+    ) -> Option<&mut (dyn TelnetChannel<Parent> + 'static)> {
+        let id = match channel {
+            None => return self.main.as_deref_mut(),
+            Some(ch) => *self.channel_map.get(&ch)?,
+        };
+        Some(&mut *self.endpoints[id])
+    }
 }
 
-// I'd love to factor these match blocks out into their own functions,
-//   but ownership (getting multiple fields from `self`) makes it hard.
-// Also, (&mut ()) doesn't live long enough.
-impl<'parent, Parent> ChannelHandler for EndpointRegistry<'parent, Parent>
+impl<'parent, 'state, Parent> Carrier<'parent, 'state, Parent, EndpointRegistry<Parent>>
 where
     Parent: ChannelHandler,
 {
-    fn on_data<'a>(&mut self, channel: Option<u8>, data: &'a [u8]) {
-        match channel {
-            None => match self.main {
-                Some(ref mut endpoint) => (*endpoint).on_data(self.parent, channel, data),
-                None => {
-                    (&mut () as &mut dyn TelnetChannel<Parent>).on_data(self.parent, channel, data)
-                }
-            },
-            Some(ch) => match self.channel_map.get(&ch) {
-                Some(&id) => {
-                    (*self.endpoints.get_mut(id).unwrap()).on_data(self.parent, channel, data)
-                }
-                None => {
-                    (&mut () as &mut dyn TelnetChannel<Parent>).on_data(self.parent, channel, data)
-                }
-            },
+    // Calls `f` with the endpoint of `channel`, unhandled channels go to the parent
+    fn route<R>(
+        &mut self,
+        channel: Option<u8>,
+        f: impl FnOnce(&mut dyn TelnetChannel<Parent>, &mut Parent) -> R,
+    ) -> R {
+        match self.state.endpoint(channel) {
+            Some(endpoint) => f(endpoint, self.parent),
+            None => f(&mut (), self.parent),
         }
     }
+}
+
+impl<'parent, 'state, Parent> ChannelHandler
+    for Carrier<'parent, 'state, Parent, EndpointRegistry<Parent>>
+where
+    Parent: ChannelHandler,
+{
+    fn on_data(&mut self, channel: Option<u8>, data: &[u8]) {
+        self.route(channel, |endpoint, parent| {
+            endpoint.on_data(parent, channel, data)
+        })
+    }
     fn on_command(&mut self, channel: Option<u8>, command: u8) {
-        match self.command_map.get(&command) {
-            Some(&id) => {
-                (*self.endpoints.get_mut(id).unwrap()).on_command(self.parent, channel, command)
-            }
-            None => (&mut () as &mut dyn TelnetChannel<Parent>).on_command(
-                self.parent,
-                channel,
-                command,
-            ),
+        match self.state.command_map.get(&command) {
+            Some(&id) => self.state.endpoints[id].on_command(self.parent, channel, command),
+            None => self.parent.on_command(channel, command),
         }
     }
 
-    fn on_enable(&mut self, channel: Option<u8>) {
-        match channel {
-            None => match self.main {
-                Some(ref mut endpoint) => (*endpoint).on_enable(self.parent, channel),
-                None => (&mut () as &mut dyn TelnetChannel<Parent>).on_enable(self.parent, channel),
-            },
-            Some(ch) => match self.channel_map.get(&ch) {
-                Some(&id) => (*self.endpoints.get_mut(id).unwrap()).on_enable(self.parent, channel),
-                None => (&mut () as &mut dyn TelnetChannel<Parent>).on_enable(self.parent, channel),
-            },
-        }
+    fn on_enable(&mut self, channel: Option<u8>, attitude: QAttitude) {
+        self.route(channel, |endpoint, parent| {
+            endpoint.on_enable(parent, channel, attitude)
+        })
     }
-    fn on_disable(&mut self, channel: Option<u8>) {
-        match channel {
-            None => match self.main {
-                Some(ref mut endpoint) => (*endpoint).on_disable(self.parent, channel),
-                None => {
-                    (&mut () as &mut dyn TelnetChannel<Parent>).on_disable(self.parent, channel)
-                }
-            },
-            Some(ch) => match self.channel_map.get(&ch) {
-                Some(&id) => {
-                    (*self.endpoints.get_mut(id).unwrap()).on_disable(self.parent, channel)
-                }
-                None => {
-                    (&mut () as &mut dyn TelnetChannel<Parent>).on_disable(self.parent, channel)
-                }
-            },
-        }
+    fn on_disable(&mut self, channel: Option<u8>, attitude: QAttitude) {
+        self.route(channel, |endpoint, parent| {
+            endpoint.on_disable(parent, channel, attitude)
+        })
     }
     fn on_focus(&mut self, channel: Option<u8>) {
-        match channel {
-            None => match self.main {
-                Some(ref mut endpoint) => (*endpoint).on_focus(self.parent, channel),
-                None => (&mut () as &mut dyn TelnetChannel<Parent>).on_focus(self.parent, channel),
-            },
-            Some(ch) => match self.channel_map.get(&ch) {
-                Some(&id) => (*self.endpoints.get_mut(id).unwrap()).on_focus(self.parent, channel),
-                None => (&mut () as &mut dyn TelnetChannel<Parent>).on_focus(self.parent, channel),
-            },
-        }
+        self.route(channel, |endpoint, parent| {
+            endpoint.on_focus(parent, channel)
+        })
     }
     fn on_blur(&mut self, channel: Option<u8>) {
-        match channel {
-            None => match self.main {
-                Some(ref mut endpoint) => (*endpoint).on_blur(self.parent, channel),
-                None => (&mut () as &mut dyn TelnetChannel<Parent>).on_blur(self.parent, channel),
-            },
-            Some(ch) => match self.channel_map.get(&ch) {
-                Some(&id) => (*self.endpoints.get_mut(id).unwrap()).on_blur(self.parent, channel),
-                None => (&mut () as &mut dyn TelnetChannel<Parent>).on_blur(self.parent, channel),
-            },
-        }
+        self.route(channel, |endpoint, parent| {
+            endpoint.on_blur(parent, channel)
+        })
     }
     fn should_enable(&mut self, channel: Option<u8>, attitude: QAttitude) -> bool {
-        match channel {
-            None => match self.main {
-                Some(ref mut endpoint) => (*endpoint).should_enable(self.parent, channel, attitude),
-                None => (&mut () as &mut dyn TelnetChannel<Parent>).should_enable(
-                    self.parent,
-                    channel,
-                    attitude,
-                ),
-            },
-            Some(ch) => match self.channel_map.get(&ch) {
-                Some(&id) => (*self.endpoints.get_mut(id).unwrap()).should_enable(
-                    self.parent,
-                    channel,
-                    attitude,
-                ),
-                None => (&mut () as &mut dyn TelnetChannel<Parent>).should_enable(
-                    self.parent,
-                    channel,
-                    attitude,
-                ),
-            },
+        self.route(channel, |endpoint, parent| {
+            endpoint.should_enable(parent, channel, attitude)
+        })
+    }
+
+    fn on_send(&mut self, data: &[u8]) {
+        self.parent.on_send(data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::codec::IAC;
+    use crate::endpoints::Accept;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[test]
+    fn replaces_a_registered_channel() {
+        let mut registry = EndpointRegistry::<()>::new();
+        registry.register_channel(1, Box::new(Accept::new(QAttitude::Local)));
+        registry.register_channel(1, Box::new(Accept::new(QAttitude::Remote)));
+        assert_eq!(registry.endpoints.len(), 1);
+        let mut carrier = Carrier {
+            parent: &mut (),
+            state: &mut registry,
+        };
+        assert!(carrier.should_enable(Some(1), QAttitude::Remote));
+        assert!(!carrier.should_enable(Some(1), QAttitude::Local));
+    }
+
+    // Counts the commands it is given
+    struct Counter(Rc<Cell<usize>>);
+
+    impl TelnetChannel<()> for Counter {
+        fn on_command(&mut self, _: &mut (), _: Option<u8>, _: u8) {
+            self.0.set(self.0.get() + 1);
+        }
+    }
+
+    #[test]
+    fn replaces_a_registered_command() {
+        let first = Rc::new(Cell::new(0));
+        let second = Rc::new(Cell::new(0));
+        let mut registry = EndpointRegistry::<()>::new();
+        registry.register_command(IAC::BRK, Box::new(Counter(first.clone())));
+        registry.register_command(IAC::BRK, Box::new(Counter(second.clone())));
+        assert_eq!(registry.endpoints.len(), 1);
+        Carrier {
+            parent: &mut (),
+            state: &mut registry,
         }
+        .on_command(None, IAC::BRK);
+        assert_eq!((first.get(), second.get()), (0, 1));
     }
 }
@@ -1,27 +1,43 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::error::Error;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::future::{self, Future};
 use std::io;
-use std::net::SocketAddr;
 use std::path::PathBuf;
 use std::process;
-use std::rc::Rc;
-use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use futures::task::{self, Task};
-use futures::{Async, Poll, Stream};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{mpsc, oneshot};
+use tokio::time::sleep;
 
-use pty;
-use time;
-use tokio_core::reactor::Handle;
-
-use history::History;
+use crate::options::{Environment, Options};
+use crate::telnet_server::ClientId;
+use crate::util::secs_to_duration;
 
 #[derive(Debug)]
 pub enum ProcessError {
     ProcessAlreadySpawned,
     NoChild,
+    SpawnFailed(io::Error),
+    // Processes of the previous child are still running
+    Leftovers,
     IoError(io::Error),
+    // The supervisor is gone, only happens while the server exits
+    Stopped,
+}
+
+impl fmt::Display for ProcessError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ProcessError::ProcessAlreadySpawned => write!(f, "process already spawned"),
+            ProcessError::NoChild => write!(f, "no child process"),
+            ProcessError::SpawnFailed(ref err) => write!(f, "failed to spawn process: {}", err),
+            ProcessError::Leftovers => write!(f, "processes of the previous child are running"),
+            ProcessError::IoError(ref err) => write!(f, "{}", err),
+            ProcessError::Stopped => write!(f, "process supervisor stopped"),
+        }
+    }
 }
 
 impl From<io::Error> for ProcessError {
@@ -30,55 +46,213 @@ impl From<io::Error> for ProcessError {
     }
 }
 
-#[allow(dead_code)]
-pub struct Process {
+/// What happened to the child, in the order it happened.
+#[derive(Debug)]
+pub enum Event {
+    Started {
+        pid: u32,
+    },
+    SpawnFailed(io::Error),
+    Output(Vec<u8>),
+    Exited {
+        pid: u32,
+        status: process::ExitStatus,
+    },
+    // A message about the child for the clients
+    Message(String),
+}
+
+/// A snapshot of the child.
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub started_at: Option<String>,
+    // Exit status of the last child that was reaped
+    pub exit_status: Option<process::ExitStatus>,
+    // Processes started by the last child are still running after it exited. Only tracked when
+    // the whole process group is killed.
+    pub leftovers: bool,
+}
+
+enum Command {
+    Spawn(oneshot::Sender<Result<(), ProcessError>>),
+    Signal(libc::c_int, oneshot::Sender<Result<(), ProcessError>>),
+    Kill(oneshot::Sender<Result<(), ProcessError>>),
+    Resize(ClientId, Option<(pty::Rows, pty::Columns)>),
+    TerminalType(ClientId, Option<String>),
+    Status(oneshot::Sender<Status>),
+    WaitExit(oneshot::Sender<()>),
+    KillLeftovers,
+    Report(String),
+}
+
+// Input for the child that is waiting in the channel while there is no child
+const INPUT_QUEUE: usize = 2048;
+// Largest chunk of output read at once
+const READ_SIZE: usize = 2048;
+
+/// Starts the supervisor of the child. It is controlled through the returned `ProcessHandle` and
+/// reports everything that happens to the child on the event channel. Has to be called within a
+/// tokio runtime.
+pub fn supervise(options: &Options) -> (ProcessHandle, mpsc::UnboundedReceiver<Event>) {
+    let (commands_tx, commands) = mpsc::unbounded_channel();
+    let (input_tx, input) = mpsc::channel(INPUT_QUEUE);
+    let (events, events_rx) = mpsc::unbounded_channel();
+    let sigchld = signal(SignalKind::child())
+        .map_err(|e| error!("Failed to handle SIGCHLD: {}", e))
+        .ok();
+    let process = Process {
+        args: options.command.clone(),
+        chdir: options.chdir.clone(),
+        killgroup: options.killgroup,
+        env: options.env.clone(),
+        term: options.term.clone(),
+        credentials: options.credentials.clone(),
+        child: None,
+        last_pgid: None,
+        exit_status: None,
+        exit_waiters: Vec::new(),
+        window_sizes: HashMap::new(),
+        terminal_types: BTreeMap::new(),
+        stdin: None,
+        stdout: None,
+        pending_input: None,
+        started_at: None,
+        commands,
+        input,
+        sigchld,
+        events,
+    };
+    tokio::spawn(process.run());
+    let handle = ProcessHandle {
+        commands: commands_tx,
+        input: input_tx,
+    };
+    (handle, events_rx)
+}
+
+/// Talks to the supervisor of the child, cheap to clone.
+#[derive(Clone)]
+pub struct ProcessHandle {
+    commands: mpsc::UnboundedSender<Command>,
+    input: mpsc::Sender<Vec<u8>>,
+}
+
+impl ProcessHandle {
+    pub async fn spawn(&self) -> Result<(), ProcessError> {
+        let reply = self.request(Command::Spawn);
+        reply.await.unwrap_or(Err(ProcessError::Stopped))
+    }
+
+    pub async fn signal(&self, signal: libc::c_int) -> Result<(), ProcessError> {
+        let reply = self.request(|reply| Command::Signal(signal, reply));
+        reply.await.unwrap_or(Err(ProcessError::Stopped))
+    }
+
+    pub async fn kill(&self) -> Result<(), ProcessError> {
+        let reply = self.request(Command::Kill);
+        reply.await.unwrap_or(Err(ProcessError::Stopped))
+    }
+
+    pub async fn status(&self) -> Result<Status, ProcessError> {
+        let reply = self.request(Command::Status);
+        reply.await.map_err(|_| ProcessError::Stopped)
+    }
+
+    // Returns when the running child has been reaped, immediately if there is none
+    pub async fn exited(&self) {
+        let _ = self.request(Command::WaitExit).await;
+    }
+
+    pub fn set_window_size(&self, client: ClientId, ws: (pty::Rows, pty::Columns)) {
+        self.send(Command::Resize(client, Some(ws)));
+    }
+
+    pub fn remove_window_size(&self, client: ClientId) {
+        self.send(Command::Resize(client, None));
+    }
+
+    // The terminal type of a client that may send input, used for TERM when the child is started
+    pub fn set_terminal_type(&self, client: ClientId, name: String) {
+        self.send(Command::TerminalType(client, Some(name)));
+    }
+
+    pub fn remove_terminal_type(&self, client: ClientId) {
+        self.send(Command::TerminalType(client, None));
+    }
+
+    pub fn kill_leftovers(&self) {
+        self.send(Command::KillLeftovers);
+    }
+
+    // Passes a message on as an event, so it is ordered with the other events
+    pub fn report(&self, message: String) {
+        self.send(Command::Report(message));
+    }
+
+    // Where the input for the child goes, it is kept for the next child while there is none
+    pub fn input(&self) -> mpsc::Sender<Vec<u8>> {
+        self.input.clone()
+    }
+
+    fn request<T, F>(&self, command: F) -> oneshot::Receiver<T>
+    where
+        F: FnOnce(oneshot::Sender<T>) -> Command,
+    {
+        let (tx, rx) = oneshot::channel();
+        // A stopped supervisor drops the reply sender, which closes the receiver
+        self.send(command(tx));
+        rx
+    }
+
+    fn send(&self, command: Command) {
+        let _ = self.commands.send(command);
+    }
+}
+
+// Owns the child and everything connected to it. Runs until all handles are dropped.
+struct Process {
     args: Vec<String>,
     chdir: PathBuf,
-    history: Rc<RefCell<History>>,
-    mailbox: Vec<String>,
-    foreground: bool,
+    killgroup: bool,
+    env: Environment,
+    term: Option<String>,
+    credentials: Option<pty::Credentials>,
     child: Option<pty::Child>,
+    // Process group of the last child, it might outlive the child itself
+    last_pgid: Option<u32>,
     exit_status: Option<process::ExitStatus>,
-    window_sizes: HashMap<SocketAddr, (pty::Rows, pty::Columns)>,
+    exit_waiters: Vec<oneshot::Sender<()>>,
+    window_sizes: HashMap<ClientId, (pty::Rows, pty::Columns)>,
+    // The client that connected first comes first
+    terminal_types: BTreeMap<ClientId, String>,
     stdin: Option<pty::PtySink>,
     stdout: Option<pty::PtyStream>,
-    handle: Handle,
-    pr_task: Option<Task>,
-    pw_task: Option<Task>,
+    // Input that the child did not take yet
+    pending_input: Option<Vec<u8>>,
     started_at: Option<String>,
+    commands: mpsc::UnboundedReceiver<Command>,
+    input: mpsc::Receiver<Vec<u8>>,
+    sigchld: Option<Signal>,
+    events: mpsc::UnboundedSender<Event>,
 }
 
 impl Process {
-    pub fn new(
-        args: Vec<String>,
-        chdir: PathBuf,
-        history: Rc<RefCell<History>>,
-        foreground: bool,
-        handle: Handle,
-    ) -> Process {
-        Process {
-            args: args,
-            chdir: chdir,
-            history: history,
-            mailbox: Vec::new(),
-            foreground: foreground,
-            child: None,
-            exit_status: None,
-            window_sizes: HashMap::new(),
-            stdin: None,
-            stdout: None,
-            handle: handle,
-            pr_task: None,
-            pw_task: None,
-            started_at: None,
-        }
-    }
-
-    pub fn spawn(&mut self) -> Result<(), ProcessError> {
+    fn spawn(&mut self) -> Result<(), ProcessError> {
         if self.child.is_some() {
             return Err(ProcessError::ProcessAlreadySpawned);
         }
-        let pty = pty::Pty::new();
+        // A new child would have to share the terminal and resources with them
+        if self.has_leftovers() {
+            self.emit(Event::Message(
+                "Not starting the child, processes of the previous child are still running"
+                    .to_owned(),
+            ));
+            return Err(ProcessError::Leftovers);
+        }
+        let mut pty = pty::Pty::new();
+        if let Some(ref credentials) = self.credentials {
+            pty.set_credentials(credentials.clone());
+        }
 
         let mut command = process::Command::new(&self.args[0]);
 
@@ -88,60 +262,148 @@ impl Process {
             }
         }
         command.current_dir(&self.chdir);
+        if self.env.clear {
+            command.env_clear();
+        }
+        for key in &self.env.unset {
+            command.env_remove(key);
+        }
+        // --term wins over the terminal of the first client, --env over both
+        let term = self.term.as_ref();
+        if let Some(term) = term.or_else(|| self.terminal_types.values().next()) {
+            command.env("TERM", term);
+        }
+        for (key, value) in &self.env.set {
+            command.env(key, value);
+        }
 
-        match pty.spawn(command, &self.handle) {
-            Err(why) => panic!("Couldn't spawn {}: {}", self.args[0], why.description()),
-            Ok(child) => {
-                self.child = Some(child);
-                self.stdin = Some(self.child.as_mut().unwrap().input().take().unwrap());
-                self.stdout = Some(self.child.as_mut().unwrap().output().take().unwrap());
-                if let Some(task) = self.pr_task.take() {
-                    task.notify();
-                }
-                if let Some(task) = self.pw_task.take() {
-                    task.notify();
-                }
+        match pty.spawn(command) {
+            Err(why) => {
+                self.emit(Event::SpawnFailed(io::Error::new(
+                    why.kind(),
+                    why.to_string(),
+                )));
+                return Err(ProcessError::SpawnFailed(why));
+            }
+            Ok(mut child) => {
+                self.stdin = child.input().take();
+                self.stdout = child.output().take();
                 self.started_at = Some(
                     time::strftime("%a, %d %b %Y %T %z", &time::now())
                         .expect("Failed to format time"),
                 );
-                println!("Launched {}", self.args[0]);
+                self.emit(Event::Started { pid: child.id() });
+                self.child = Some(child);
+                self.update_window_size();
             }
         };
         Ok(())
     }
 
-    pub fn started_at(&self) -> Option<&String> {
-        self.started_at.as_ref()
+    fn emit(&self, event: Event) {
+        let _ = self.events.send(event);
     }
 
-    pub fn wait(&mut self) -> Result<process::ExitStatus, ProcessError> {
-        if let Some(mut child) = self.child.take() {
-            return child.wait().map_err(|e| From::from(e));
+    fn status(&self) -> Status {
+        Status {
+            started_at: self.started_at.clone(),
+            exit_status: self.exit_status,
+            leftovers: self.has_leftovers(),
+        }
+    }
+
+    // Reaps the child if it has exited
+    fn reap(&mut self) {
+        let status = match self.child {
+            Some(ref mut child) => match child.try_wait() {
+                Ok(Some(status)) => status,
+                Ok(None) => return,
+                Err(e) => {
+                    warn!("Failed to wait for the child: {}", e);
+                    return;
+                }
+            },
+            // A failed spawn also raises SIGCHLD, but then there is no child to wait for
+            None => {
+                trace!("SIGCHLD without a child");
+                return;
+            }
+        };
+        if let Some(stdout) = self.stdout.take() {
+            // Pick up what the child wrote right before it exited
+            let mut buf = [0; READ_SIZE];
+            while let Ok(len) = stdout.try_read(&mut buf) {
+                if len == 0 {
+                    break;
+                }
+                self.emit(Event::Output(buf[..len].to_vec()));
+            }
+        }
+        // Input that was not written yet is kept for the next child
+        self.stdin = None;
+        let pid = self.child.take().unwrap().id();
+        self.last_pgid = Some(pid);
+        self.exit_status = Some(status);
+        for waiter in self.exit_waiters.drain(..) {
+            let _ = waiter.send(());
+        }
+        self.emit(Event::Exited { pid, status });
+    }
+
+    fn kill(&mut self) -> Result<(), ProcessError> {
+        if self.killgroup {
+            return self.signal(libc::SIGKILL);
+        }
+        if let Some(ref mut child) = self.child {
+            return child.kill().map_err(From::from);
         }
         Err(ProcessError::NoChild)
     }
 
-    pub fn kill(&mut self) -> Result<(), ProcessError> {
+    fn signal(&mut self, signal: libc::c_int) -> Result<(), ProcessError> {
         if let Some(ref mut child) = self.child {
-            return child.kill().map_err(|e| From::from(e));
+            if self.killgroup {
+                return child.signal_group(signal).map_err(From::from);
+            }
+            return child.signal(signal).map_err(From::from);
         }
         Err(ProcessError::NoChild)
     }
 
-    pub fn id(&self) -> Option<u32> {
-        if let Some(ref child) = self.child {
-            Some(child.id())
-        } else {
-            None
+    fn has_leftovers(&self) -> bool {
+        match self.last_pgid {
+            Some(pgid) if self.killgroup && self.child.is_none() => pty::process_group_alive(pgid),
+            _ => false,
+        }
+    }
+
+    fn kill_leftovers(&mut self) {
+        if let Some(pgid) = self.last_pgid {
+            let _ = pty::signal_process_group(pgid, libc::SIGKILL);
+        }
+    }
+
+    fn resize(&mut self, client: ClientId, ws: Option<(pty::Rows, pty::Columns)>) {
+        match ws {
+            Some(ws) => {
+                self.window_sizes.insert(client, ws);
+            }
+            None => {
+                if self.window_sizes.remove(&client).is_none() {
+                    return;
+                }
+            }
         }
+        self.update_window_size();
     }
 
-    pub fn set_window_size(&mut self, addr: SocketAddr, ws: (pty::Rows, pty::Columns)) {
-        //println!("Store {:?},{:?} for {:?}", ws.0, ws.1, addr);
-        self.window_sizes.insert(addr, ws);
-        let mut min_ws = (From::from(u16::max_value()), From::from(u16::max_value()));
-        for (_, ws) in &self.window_sizes {
+    // The child gets the smallest window size of all connected clients
+    fn update_window_size(&mut self) {
+        if self.window_sizes.is_empty() {
+            return;
+        }
+        let mut min_ws = (From::from(u16::MAX), From::from(u16::MAX));
+        for ws in self.window_sizes.values() {
             if ws.0 < min_ws.0 {
                 min_ws.0 = ws.0;
             }
@@ -154,57 +416,252 @@ impl Process {
         }
     }
 
-    pub fn set_pr_task(&mut self, task: Task) {
-        self.pr_task = Some(task);
+    fn handle_command(&mut self, command: Command) {
+        match command {
+            Command::Spawn(reply) => {
+                let res = self.spawn();
+                let _ = reply.send(res);
+            }
+            Command::Signal(signal, reply) => {
+                let res = self.signal(signal);
+                let _ = reply.send(res);
+            }
+            Command::Kill(reply) => {
+                let res = self.kill();
+                let _ = reply.send(res);
+            }
+            Command::Resize(client, ws) => self.resize(client, ws),
+            Command::TerminalType(client, Some(name)) => {
+                // Terminal types are sent in upper case, terminfo names are lower case
+                self.terminal_types.insert(client, name.to_lowercase());
+            }
+            Command::TerminalType(client, None) => {
+                self.terminal_types.remove(&client);
+            }
+            Command::Status(reply) => {
+                let _ = reply.send(self.status());
+            }
+            Command::WaitExit(reply) => {
+                if self.child.is_some() {
+                    self.exit_waiters.push(reply);
+                } else {
+                    let _ = reply.send(());
+                }
+            }
+            Command::KillLeftovers => self.kill_leftovers(),
+            Command::Report(message) => self.emit(Event::Message(message)),
+        }
+    }
+
+    async fn run(mut self) {
+        let mut buf = vec![0; READ_SIZE];
+        loop {
+            tokio::select! {
+                command = self.commands.recv() => match command {
+                    Some(command) => self.handle_command(command),
+                    None => break,
+                },
+                res = read_output(&mut self.stdout, &mut buf) => match res {
+                    Ok(len) if len > 0 => self.emit(Event::Output(buf[..len].to_vec())),
+                    _ => {
+                        self.stdout = None;
+                        // The child is most likely gone
+                        self.reap();
+                    }
+                },
+                res = next_signal(&mut self.sigchld) => match res {
+                    Some(()) => self.reap(),
+                    None => self.sigchld = None,
+                },
+                item = self.input.recv(), if self.stdin.is_some() && self.pending_input.is_none() => {
+                    self.pending_input = item;
+                }
+                res = write_input(&mut self.stdin, self.pending_input.as_deref()) => match res {
+                    Ok(len) => {
+                        let pending = self.pending_input.as_mut().unwrap();
+                        pending.drain(..len);
+                        if pending.is_empty() {
+                            self.pending_input = None;
+                        }
+                    }
+                    Err(e) => {
+                        debug!("Failed to write to the child: {}", e);
+                        self.stdin = None;
+                    }
+                },
+            }
+        }
+        debug!("All process handles dropped, stopping the supervisor");
     }
+}
 
-    pub fn set_pw_task(&mut self, task: Task) {
-        self.pw_task = Some(task);
+// Without a child these never return, like the disabled branches of a select
+async fn read_output(stdout: &mut Option<pty::PtyStream>, buf: &mut [u8]) -> io::Result<usize> {
+    match *stdout {
+        Some(ref mut stdout) => stdout.read(buf).await,
+        None => future::pending().await,
     }
 }
 
-pub struct ProcessWriters {
-    inner: Arc<Mutex<Process>>,
+async fn write_input(stdin: &mut Option<pty::PtySink>, data: Option<&[u8]>) -> io::Result<usize> {
+    match (stdin, data) {
+        (Some(stdin), Some(data)) => stdin.write(data).await,
+        _ => future::pending().await,
+    }
 }
 
-impl ProcessWriters {
-    pub fn new(inner: Arc<Mutex<Process>>) -> ProcessWriters {
-        ProcessWriters { inner: inner }
+async fn next_signal(signal: &mut Option<Signal>) -> Option<()> {
+    match *signal {
+        Some(ref mut signal) => signal.recv().await,
+        None => future::pending().await,
     }
 }
 
-impl Stream for ProcessWriters {
-    type Item = pty::PtySink;
-    type Error = io::Error;
+// Asks the child to exit with `signal`. If it is still alive after `timeout`, or as soon as
+// `hurry` returns, it is killed.
+pub async fn stop<F>(child: ProcessHandle, signal: libc::c_int, timeout: Duration, hurry: F)
+where
+    F: Future<Output = ()>,
+{
+    if child.signal(signal).await.is_err() {
+        // Nothing to stop
+        return;
+    }
+    tokio::select! {
+        _ = child.exited() => return,
+        _ = sleep(timeout) => (),
+        _ = hurry => (),
+    }
+    child.report("Child did not stop in time, sending SIGKILL".to_owned());
+    let _ = child.kill().await;
+    child.exited().await;
+}
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        if let Some(stdin) = self.inner.lock().unwrap().stdin.take() {
-            return Ok(Async::Ready(Some(stdin)));
+// Kills whatever is left of the last child's process group and returns once it is gone, or
+// after a few seconds if the processes refuse to go away.
+pub async fn kill_leftovers(child: ProcessHandle) {
+    for attempt in 0.. {
+        match child.status().await {
+            Ok(ref status) if status.leftovers => (),
+            _ => return,
+        }
+        if attempt == 25 {
+            child.report("Processes of the previous child are still running".to_owned());
+            return;
+        }
+        if attempt == 0 {
+            child.report("Killing leftover processes of the previous child".to_owned());
         }
-        self.inner.lock().unwrap().set_pw_task(task::current());
-        Ok(Async::NotReady)
+        child.kill_leftovers();
+        sleep(Duration::from_millis(200)).await;
     }
 }
 
-pub struct ProcessReaders {
-    inner: Arc<Mutex<Process>>,
+// Starts the child after `holdoff` seconds unless `restart` says otherwise. If the child can not be
+// spawned, or leftovers of the old one refuse to die, it is retried after another holdoff.
+pub async fn restart_later<F>(child: ProcessHandle, holdoff: f64, restart: F)
+where
+    F: Fn() -> bool,
+{
+    loop {
+        sleep(secs_to_duration(holdoff)).await;
+        // Don't start a new child next to the remains of the old one
+        kill_leftovers(child.clone()).await;
+        if !restart() {
+            return;
+        }
+        match child.spawn().await {
+            Err(ProcessError::SpawnFailed(..)) | Err(ProcessError::Leftovers) => {
+                child.report(format!("Will retry in {:.2}s", holdoff));
+            }
+            _ => return,
+        }
+    }
 }
 
-impl ProcessReaders {
-    pub fn new(inner: Arc<Mutex<Process>>) -> ProcessReaders {
-        ProcessReaders { inner: inner }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn reports_output_and_exit() {
+        let options = Options {
+            command: vec![
+                "sh".to_owned(),
+                "-c".to_owned(),
+                "echo hi; exit 3".to_owned(),
+            ],
+            ..Default::default()
+        };
+        let (child, mut events) = supervise(&options);
+        child.spawn().await.unwrap();
+        match events.recv().await {
+            Some(Event::Started { .. }) => (),
+            event => panic!("unexpected {:?}", event),
+        }
+        let mut output = Vec::new();
+        loop {
+            match events.recv().await {
+                Some(Event::Output(data)) => output.extend(data),
+                Some(Event::Exited { .. }) => break,
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+        assert_eq!(output, b"hi\r\n");
+        let status = child.status().await.unwrap();
+        assert_eq!(status.exit_status.and_then(|status| status.code()), Some(3));
     }
-}
 
-impl Stream for ProcessReaders {
-    type Item = pty::PtyStream;
-    type Error = io::Error;
+    async fn output_of(
+        child: &ProcessHandle,
+        events: &mut mpsc::UnboundedReceiver<Event>,
+    ) -> Vec<u8> {
+        child.spawn().await.unwrap();
+        let mut output = Vec::new();
+        loop {
+            match events.recv().await {
+                Some(Event::Output(data)) => output.extend(data),
+                Some(Event::Exited { .. }) => return output,
+                _ => (),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn takes_term_from_first_client() {
+        let mut options = Options {
+            command: vec!["sh".to_owned(), "-c".to_owned(), "echo $TERM".to_owned()],
+            ..Default::default()
+        };
+        let (child, mut events) = supervise(&options);
+        child.set_terminal_type(2, "VT100".to_owned());
+        child.set_terminal_type(1, "XTERM-256COLOR".to_owned());
+        assert_eq!(output_of(&child, &mut events).await, b"xterm-256color\r\n");
+        child.remove_terminal_type(1);
+        assert_eq!(output_of(&child, &mut events).await, b"vt100\r\n");
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        if let Some(stdout) = self.inner.lock().unwrap().stdout.take() {
-            return Ok(Async::Ready(Some(stdout)));
+        options.term = Some("dumb".to_owned());
+        let (child, mut events) = supervise(&options);
+        child.set_terminal_type(1, "VT100".to_owned());
+        assert_eq!(output_of(&child, &mut events).await, b"dumb\r\n");
+    }
+
+    #[tokio::test]
+    async fn refuses_to_start_next_to_leftovers() {
+        let options = Options {
+            command: vec!["sh".to_owned(), "-c".to_owned(), "sleep 10 &".to_owned()],
+            killgroup: true,
+            ..Default::default()
+        };
+        let (child, mut events) = supervise(&options);
+        output_of(&child, &mut events).await;
+        assert!(child.status().await.unwrap().leftovers);
+        match child.spawn().await {
+            Err(ProcessError::Leftovers) => (),
+            res => panic!("unexpected {:?}", res),
         }
-        self.inner.lock().unwrap().set_pr_task(task::current());
-        Ok(Async::NotReady)
+        kill_leftovers(child.clone()).await;
+        child.spawn().await.unwrap();
+        child.kill().await.unwrap();
     }
 }
@@ -0,0 +1,416 @@
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use toml::value::{Table, Value};
+
+use crate::history::Replay;
+use crate::options::{self, Bind, Environment, Options};
+
+// Keys are named after the long command line flags, plus `command` for the child command line.
+const KEYS: &[&str] = &[
+    "command",
+    "quiet",
+    "log-level",
+    "syslog",
+    "serverlog",
+    "wait",
+    "noautorestart",
+    "noinfo",
+    "foreground",
+    "interactive",
+    "holdoff",
+    "stopsig",
+    "stoptimeout",
+    "killsig",
+    "killtimeout",
+    "killgroup",
+    "env",
+    "unset-env",
+    "clear-env",
+    "env-file",
+    "term",
+    "user",
+    "group",
+    "bind",
+    "logbind",
+    "logfile",
+    "logtimestamps",
+    "logmaxsize",
+    "logmaxage",
+    "logkeep",
+    "histsize",
+    "histlines",
+    "histfile",
+    "killcmd",
+    "autorestartcmd",
+    "restartcmd",
+    "logoutcmd",
+    "replaycmd",
+    "replay",
+    "clientqueue",
+    "slowclients",
+    "chdir",
+];
+
+/// Reads the configuration file at `path` into `options`.
+pub fn load(path: &Path, options: &mut Options) -> Result<(), String> {
+    let mut content = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut content))
+        .map_err(|e| format!("Failed to read config file {}: {}", path.display(), e))?;
+    apply(&content, options).map_err(|e| format!("Invalid config file {}: {}", path.display(), e))
+}
+
+/// Sets every option present in the TOML document `content`.
+pub fn apply(content: &str, options: &mut Options) -> Result<(), String> {
+    let table = content.parse::<Value>().map_err(|e| e.to_string())?;
+    let table = match table {
+        Value::Table(table) => table,
+        _ => return Err("expected a table".to_owned()),
+    };
+
+    let unknown: Vec<&str> = table
+        .keys()
+        .filter(|key| !KEYS.contains(&key.as_str()))
+        .map(|key| key.as_str())
+        .collect();
+    if !unknown.is_empty() {
+        return Err(format!(
+            "unknown keys: {} (valid keys are {})",
+            unknown.join(", "),
+            KEYS.join(", ")
+        ));
+    }
+
+    if let Some(command) = strings(&table, "command")? {
+        options.command = command;
+    }
+    if let Some(quiet) = boolean(&table, "quiet")? {
+        options.quiet = quiet;
+    }
+    if let Some(level) = string(&table, "log-level")? {
+        options.log_level = options::parse_log_level(level)
+            .ok_or_else(|| format!("Invalid log level '{}'", level))?;
+    }
+    if let Some(syslog) = boolean(&table, "syslog")? {
+        options.syslog = syslog;
+    }
+    if let Some(path) = string(&table, "serverlog")? {
+        options.serverlog = Some(PathBuf::from(path));
+    }
+    if let Some(wait) = boolean(&table, "wait")? {
+        options.autostart = !wait;
+    }
+    if let Some(noautorestart) = boolean(&table, "noautorestart")? {
+        options.autorestart = !noautorestart;
+    }
+    if let Some(noinfo) = boolean(&table, "noinfo")? {
+        options.noinfo = noinfo;
+    }
+    if let Some(foreground) = boolean(&table, "foreground")? {
+        options.foreground = foreground;
+    }
+    if let Some(interactive) = boolean(&table, "interactive")? {
+        options.interactive = interactive;
+    }
+    if let Some(holdoff) = number(&table, "holdoff")? {
+        options.holdoff = holdoff;
+    }
+    if let Some(sig) = signal(&table, "stopsig")? {
+        options.stopsig = sig;
+    }
+    if let Some(stoptimeout) = number(&table, "stoptimeout")? {
+        options.stoptimeout = stoptimeout;
+    }
+    if let Some(sig) = signal(&table, "killsig")? {
+        options.killsig = sig;
+    }
+    if let Some(killtimeout) = number(&table, "killtimeout")? {
+        options.killtimeout = Some(killtimeout);
+    }
+    if let Some(killgroup) = boolean(&table, "killgroup")? {
+        options.killgroup = killgroup;
+    }
+    if let Some(clear) = boolean(&table, "clear-env")? {
+        options.env.clear = clear;
+    }
+    if let Some(keys) = strings(&table, "unset-env")? {
+        keys.into_iter().for_each(|key| options.env.unset_var(key));
+    }
+    if let Some(path) = string(&table, "env-file")? {
+        for var in Environment::read_file(Path::new(path))? {
+            options.env.add_var(var);
+        }
+    }
+    if let Some(vars) = strings(&table, "env")? {
+        for var in vars {
+            options.env.add_var(Environment::parse_var(&var)?);
+        }
+    }
+    if let Some(term) = string(&table, "term")? {
+        options.term = Some(term.to_owned());
+    }
+    if let Some(user) = string(&table, "user")? {
+        options.user = Some(user.to_owned());
+    }
+    if let Some(group) = string(&table, "group")? {
+        options.group = Some(group.to_owned());
+    }
+    if let Some(binds) = binds(&table, "bind")? {
+        options.binds = binds;
+    }
+    if let Some(binds) = binds(&table, "logbind")? {
+        options.logbinds = binds;
+    }
+    if let Some(logfiles) = strings(&table, "logfile")? {
+        options.logfiles = logfiles.into_iter().map(PathBuf::from).collect();
+    }
+    if let Some(logtimestamps) = boolean(&table, "logtimestamps")? {
+        options.logtimestamps = logtimestamps;
+    }
+    if let Some(size) = table.get("logmaxsize") {
+        let size = match *size {
+            Value::Integer(size) if size > 0 => Some(size as u64),
+            Value::String(ref size) => options::parse_size(size),
+            _ => None,
+        };
+        options.logrotation.max_size =
+            Some(size.ok_or_else(|| "'logmaxsize' must be a size like 10M".to_owned())?);
+    }
+    if let Some(age) = table.get("logmaxage") {
+        let age = match *age {
+            Value::Integer(age) if age > 0 => Some(Duration::from_secs(age as u64)),
+            Value::String(ref age) => options::parse_age(age),
+            _ => None,
+        };
+        options.logrotation.max_age =
+            Some(age.ok_or_else(|| "'logmaxage' must be a time like 3600 or 1d".to_owned())?);
+    }
+    if let Some(keep) = table.get("logkeep") {
+        options.logrotation.keep = match keep.as_integer() {
+            Some(keep) if keep >= 0 => keep as usize,
+            _ => return Err("'logkeep' must be a positive integer".to_owned()),
+        };
+    }
+    if let Some(size) = table.get("histsize") {
+        let size = match *size {
+            Value::Integer(size) if size > 0 => Some(size as u64),
+            Value::String(ref size) => options::parse_size(size),
+            _ => None,
+        };
+        options.history_size =
+            size.ok_or_else(|| "'histsize' must be a size like 1M".to_owned())?;
+    }
+    if let Some(path) = string(&table, "histfile")? {
+        options.histfile = Some(PathBuf::from(path));
+    }
+    if let Some(lines) = table.get("histlines") {
+        options.history_lines = match lines.as_integer() {
+            Some(lines) if lines > 0 => Some(lines as usize),
+            _ => return Err("'histlines' must be a positive integer".to_owned()),
+        };
+    }
+    if let Some(cmd) = shortcut(&table, "killcmd")? {
+        options.killcmd = cmd;
+    }
+    if let Some(cmd) = shortcut(&table, "autorestartcmd")? {
+        options.togglecmd = cmd;
+    }
+    if let Some(cmd) = shortcut(&table, "restartcmd")? {
+        options.restartcmd = cmd;
+    }
+    if let Some(cmd) = shortcut(&table, "logoutcmd")? {
+        options.logoutcmd = cmd;
+    }
+    if let Some(cmd) = shortcut(&table, "replaycmd")? {
+        options.replaycmd = cmd;
+    }
+    if let Some(replay) = table.get("replay") {
+        let replay = match *replay {
+            Value::Integer(lines) if lines >= 0 => Some(Replay::Lines(lines as usize)),
+            Value::String(ref mode) => options::parse_replay(mode),
+            _ => None,
+        };
+        options.replay = replay
+            .ok_or_else(|| "'replay' must be all, none, start or a number of lines".to_owned())?;
+    }
+    if let Some(size) = table.get("clientqueue") {
+        let size = match *size {
+            Value::Integer(size) if size > 0 => Some(size as u64),
+            Value::String(ref size) => options::parse_size(size),
+            _ => None,
+        };
+        options.client_queue =
+            size.ok_or_else(|| "'clientqueue' must be a size like 64K".to_owned())?;
+    }
+    if let Some(policy) = string(&table, "slowclients")? {
+        options.slow_clients = options::parse_slow_clients(policy)
+            .ok_or_else(|| "'slowclients' must be skip or disconnect".to_owned())?;
+    }
+    if let Some(chdir) = string(&table, "chdir")? {
+        options.chdir = PathBuf::from(chdir);
+    }
+    Ok(())
+}
+
+fn boolean(table: &Table, key: &str) -> Result<Option<bool>, String> {
+    match table.get(key) {
+        Some(value) => value
+            .as_bool()
+            .map(Some)
+            .ok_or_else(|| format!("'{}' must be true or false", key)),
+        None => Ok(None),
+    }
+}
+
+// Accepts both integers and floats
+fn number(table: &Table, key: &str) -> Result<Option<f64>, String> {
+    match table.get(key) {
+        Some(Value::Float(f)) => Ok(Some(*f)),
+        Some(Value::Integer(i)) => Ok(Some(*i as f64)),
+        Some(..) => Err(format!("'{}' must be a number", key)),
+        None => Ok(None),
+    }
+}
+
+fn string<'a>(table: &'a Table, key: &str) -> Result<Option<&'a str>, String> {
+    match table.get(key) {
+        Some(value) => value
+            .as_str()
+            .map(Some)
+            .ok_or_else(|| format!("'{}' must be a string", key)),
+        None => Ok(None),
+    }
+}
+
+// Accepts a single string or an array of strings
+fn strings(table: &Table, key: &str) -> Result<Option<Vec<String>>, String> {
+    let err = || format!("'{}' must be a string or an array of strings", key);
+    match table.get(key) {
+        Some(Value::String(s)) => Ok(Some(vec![s.clone()])),
+        Some(Value::Array(values)) => values
+            .iter()
+            .map(|value| value.as_str().map(String::from).ok_or_else(err))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Some),
+        Some(..) => Err(err()),
+        None => Ok(None),
+    }
+}
+
+fn binds(table: &Table, key: &str) -> Result<Option<Vec<Bind>>, String> {
+    match strings(table, key)? {
+        Some(values) => {
+            let mut binds = Vec::new();
+            for value in values {
+                binds.append(&mut Bind::parse_all(&value)?);
+            }
+            Ok(Some(binds))
+        }
+        None => Ok(None),
+    }
+}
+
+// Accepts a signal name or number
+fn signal(table: &Table, key: &str) -> Result<Option<::libc::c_int>, String> {
+    let sig = match table.get(key) {
+        Some(Value::String(s)) => options::parse_signal(s),
+        Some(Value::Integer(i)) => options::parse_signal(&i.to_string()),
+        Some(..) => None,
+        None => return Ok(None),
+    };
+    sig.map(Some)
+        .ok_or_else(|| format!("'{}' must be a signal name or number", key))
+}
+
+fn shortcut(table: &Table, key: &str) -> Result<Option<Option<u8>>, String> {
+    match string(table, key)? {
+        Some(cmd) => options::parse_shortcut(cmd.as_bytes())
+            .map(Some)
+            .map_err(|_| format!("Failed to parse '{}' for '{}'", cmd, key)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tempdir;
+
+    #[test]
+    fn apply_sets_options() {
+        let mut options = Options::default();
+        let config = r#"
+            command = ["softIoc", "st.cmd"]
+            bind = ["3000", "unix:/tmp/ioc.sock,replay=none"]
+            logbind = "*:4000"
+            holdoff = 2
+            histsize = 100
+            histlines = 10
+            killcmd = "^K"
+            logoutcmd = "^]"
+            stopsig = "INT"
+            killtimeout = 1.5
+            noautorestart = true
+            unset-env = "DISPLAY"
+            env = ["EPICS_CA_ADDR_LIST=10.0.0.1", "EMPTY=", "EPICS_CA_ADDR_LIST=10.0.0.255"]
+        "#;
+        apply(config, &mut options).unwrap();
+        assert_eq!(options.command, vec!["softIoc", "st.cmd"]);
+        assert_eq!(options.binds.len(), 2);
+        assert_eq!(options.binds[1].replay, Some(Replay::Nothing));
+        assert_eq!(options.logbinds[0].to_string(), "0.0.0.0:4000");
+        assert_eq!(options.holdoff, 2.0);
+        assert_eq!(options.history_size, 100);
+        assert_eq!(options.history_lines, Some(10));
+        assert_eq!(options.killcmd, Some(0x0b));
+        assert_eq!(options.logoutcmd, Some(0x1d));
+        assert_eq!(options.stopsig, ::libc::SIGINT);
+        assert_eq!(options.killtimeout, Some(1.5));
+        assert!(!options.autorestart);
+        assert_eq!(options.env.unset, vec!["DISPLAY"]);
+        assert_eq!(
+            options.env.set,
+            vec![
+                ("EMPTY".to_owned(), "".to_owned()),
+                ("EPICS_CA_ADDR_LIST".to_owned(), "10.0.0.255".to_owned()),
+            ]
+        );
+    }
+
+    #[test]
+    fn apply_rejects_unknown_keys() {
+        let mut options = Options::default();
+        let err = apply("holdof = 1\nbinds = \"3000\"\nhistsize = 1", &mut options).unwrap_err();
+        assert!(err.starts_with("unknown keys: binds, holdof"), "{}", err);
+    }
+
+    #[test]
+    fn apply_rejects_bad_values() {
+        let mut options = Options::default();
+        assert!(apply("holdoff = \"soon\"", &mut options).is_err());
+        assert!(apply("stopsig = \"SIGFOO\"", &mut options).is_err());
+        assert!(apply("bind = 3000", &mut options).is_err());
+        assert!(apply("env = \"=1\"", &mut options).is_err());
+    }
+
+    #[test]
+    fn env_file_replaces_earlier_values() {
+        let dir = tempdir("env");
+        let path = dir.join("env");
+        ::std::fs::write(&path, "A=1\nB=2\nA=3\n").unwrap();
+        let mut options = Options::default();
+        options.env.add_var(("B".to_owned(), "0".to_owned()));
+        let config = format!("env-file = {:?}", path.display().to_string());
+        apply(&config, &mut options).unwrap();
+        assert_eq!(
+            options.env.set,
+            vec![
+                ("B".to_owned(), "2".to_owned()),
+                ("A".to_owned(), "3".to_owned()),
+            ]
+        );
+    }
+}
@@ -1,174 +1,769 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::cmp;
 use std::collections::vec_deque::Iter;
 use std::collections::VecDeque;
-use std::fs::{File, OpenOptions};
+use std::fmt;
+use std::fs;
 use std::io;
-use std::io::Write;
 use std::iter::Skip;
+use std::path::Path;
 use std::rc::Rc;
 
-use futures::task::{self, Task};
-use futures::{Async, AsyncSink, Poll, Sink, StartSend, Stream};
+use byteorder::{BigEndian, ByteOrder};
 
-use options::Options;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+
+use futures::Stream;
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+
+use crate::logfile::{self, LogFile, LogWriter, Rotation};
+use crate::options::Options;
 
 #[derive(Debug, PartialEq)]
 pub enum HistoryLine {
     Child { message: Vec<u8> },
     //Command (Vec<u8>),
-    //Info {message: String},
+    // Events of the server itself, like the child being started or stopped
+    Info { message: String },
+}
+
+impl HistoryLine {
+    // Position after the last byte, readers keep this to notice output merged into the line
+    fn end(&self) -> usize {
+        match self {
+            HistoryLine::Child { message } => message.len(),
+            HistoryLine::Info { .. } => 1,
+        }
+    }
+
+    fn bytes(&self) -> u64 {
+        match self {
+            HistoryLine::Child { message } => message.len() as u64,
+            HistoryLine::Info { message } => message.len() as u64,
+        }
+    }
+
+    fn lines(&self) -> usize {
+        match self {
+            HistoryLine::Child { message } => message.iter().filter(|&&b| b == b'\n').count(),
+            HistoryLine::Info { .. } => 1,
+        }
+    }
+
+    // A record for the history file: a tag, the length and the data
+    fn encode(&self) -> Vec<u8> {
+        let (tag, data) = match self {
+            HistoryLine::Child { message } => (RECORD_CHILD, message.as_slice()),
+            HistoryLine::Info { message } => (RECORD_INFO, message.as_bytes()),
+        };
+        let mut record = vec![tag, 0, 0, 0, 0];
+        BigEndian::write_u32(&mut record[1..], data.len() as u32);
+        record.extend_from_slice(data);
+        record
+    }
+
+    // Reads the records of a history file, a record cut short by a crash or with an unknown tag
+    // ends it. Also returns the length of the valid records.
+    fn decode(data: &[u8]) -> (Vec<HistoryLine>, usize) {
+        let mut lines = Vec::new();
+        let mut pos = 0;
+        while let Some(header) = data.get(pos..pos + 5) {
+            let len = BigEndian::read_u32(&header[1..]) as usize;
+            let message = match data.get(pos + 5..pos + 5 + len) {
+                Some(message) => message,
+                None => break,
+            };
+            lines.push(match header[0] {
+                RECORD_CHILD => HistoryLine::Child {
+                    message: message.to_vec(),
+                },
+                RECORD_INFO => HistoryLine::Info {
+                    message: String::from_utf8_lossy(message).into_owned(),
+                },
+                _ => break,
+            });
+            pos += 5 + len;
+        }
+        (lines, pos)
+    }
+}
+
+fn truncate(path: &Path, len: u64) -> io::Result<()> {
+    fs::OpenOptions::new().write(true).open(path)?.set_len(len)
+}
+
+const RECORD_CHILD: u8 = b'C';
+const RECORD_INFO: u8 = b'I';
+
+/// How much of the history a client is sent when it connects.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Replay {
+    All,
+    Lines(usize),
+    Nothing,
+    // Everything since the child was started the last time
+    SinceStart,
+}
+
+impl fmt::Display for Replay {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Replay::All => write!(f, "all"),
+            Replay::Lines(lines) => write!(f, "{}", lines),
+            Replay::Nothing => write!(f, "none"),
+            Replay::SinceStart => write!(f, "start"),
+        }
+    }
+}
+
+/// What happens to a client that is too far behind the newest output.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SlowClient {
+    // Skip output to catch up, the client is told how much is missing
+    Skip,
+    Disconnect,
 }
 
+// Small reads from the child are merged into chunks of up to this size
+const CHUNK_SIZE: usize = 4096;
+// Readers hand out at most this much at once, so a long replay is sent in pieces
+const READ_SIZE: usize = 16 * 1024;
+
 pub struct History {
     buffers: VecDeque<HistoryLine>,
-    histsize: usize,
+    // Byte position of every line in all output so far
+    starts: VecDeque<u64>,
+    total: u64,
+    max_bytes: u64,
+    max_lines: Option<usize>,
+    // What is in the buffers right now
+    bytes: u64,
+    lines: usize,
+    // Whether the oldest line lost its beginning when older output was dropped
+    cut: bool,
+    // Index of the first line written after the child was started the last time
+    started: Option<usize>,
     offset: usize,
-    tasks: Vec<Task>,
-    logfiles: Vec<File>,
+    tasks: Vec<Waker>,
+    logfiles: Vec<LogWriter>,
+    // Keeps the history over restarts of the server
+    histfile: Option<LogWriter>,
+    // Problems with the logfiles, reported by their writer threads
+    logfile_events: Option<UnboundedReceiver<String>>,
 }
 
 impl History {
     pub fn new(options: &Options) -> History {
-        let buf = VecDeque::with_capacity(options.history_size);
+        let buf = VecDeque::new();
+        let (events_tx, events_rx) = mpsc::unbounded_channel();
         let logfiles = options
             .logfiles
             .iter()
-            .map(|file| {
-                OpenOptions::new()
-                    .write(true)
-                    .create(true)
-                    .open(file)
-                    .expect("Failed to open logfile")
+            .map(|path| {
+                let file = LogFile::open(path, options)
+                    .unwrap_or_else(|e| panic!("Failed to open logfile {}: {}", path.display(), e));
+                LogWriter::new(file, events_tx.clone())
             })
             .collect();
-
-        History {
+        let mut history = History {
             buffers: buf,
-            histsize: options.history_size,
+            starts: VecDeque::new(),
+            total: 0,
+            max_bytes: options.history_size,
+            max_lines: options.history_lines,
+            bytes: 0,
+            lines: 0,
+            cut: false,
+            started: None,
             offset: 0,
             tasks: Vec::new(),
-            logfiles: logfiles,
+            logfiles,
+            histfile: None,
+            logfile_events: Some(events_rx),
+        };
+        if let Some(ref path) = options.histfile {
+            history.restore(path);
+        }
+        // Opened after the restore, which may cut off a broken record at the end
+        history.histfile = options.histfile.as_ref().map(|path| {
+            // Two files of the history size each always hold the whole history
+            let rotation = Rotation {
+                max_size: Some(options.history_size),
+                max_age: None,
+                keep: 1,
+            };
+            let file = LogFile::new(path, false, rotation)
+                .unwrap_or_else(|e| panic!("Failed to open {}: {}", path.display(), e));
+            LogWriter::new(file, events_tx)
+        });
+        history
+    }
+
+    // Loads the history written by an earlier run of the server
+    fn restore(&mut self, path: &Path) {
+        let mut restored = 0;
+        for path in &[logfile::numbered(path, 1), path.to_owned()] {
+            match fs::read(path) {
+                Ok(data) => {
+                    let (lines, valid) = HistoryLine::decode(&data);
+                    for line in lines {
+                        restored += line.bytes();
+                        self.store(line);
+                    }
+                    // New records must not be appended to a broken one
+                    if valid < data.len() {
+                        warn!(
+                            "Dropping {} bytes of broken records at the end of {}",
+                            data.len() - valid,
+                            path.display()
+                        );
+                        if let Err(e) = truncate(path, valid as u64) {
+                            warn!("Failed to truncate {}: {}", path.display(), e);
+                        }
+                    }
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+                Err(e) => warn!("Failed to read {}: {}", path.display(), e),
+            }
+        }
+        if restored > 0 {
+            let message = "History from before the restart of the server ends here".to_owned();
+            self.store(HistoryLine::Info { message });
         }
     }
 
-    pub fn park(&mut self, task: Task) {
+    // The messages of the logfile writers, they should be passed on to `event`
+    pub fn take_logfile_events(&mut self) -> Option<UnboundedReceiver<String>> {
+        self.logfile_events.take()
+    }
+
+    pub fn park(&mut self, task: Waker) {
         self.tasks.push(task);
     }
 
     pub fn unpark(&mut self) {
         for task in self.tasks.drain(..) {
-            task.notify();
+            task.wake();
+        }
+    }
+
+    // Reports an event on stdout and to the clients
+    pub fn event(&mut self, message: String) {
+        info!("{}", message);
+        self.push(HistoryLine::Info { message });
+        self.unpark();
+    }
+
+    // Opens all logfiles again, they might have been moved away by logrotate
+    pub fn reopen_logfiles(&mut self) {
+        for file in &self.logfiles {
+            file.reopen();
         }
     }
 
+    // Marks the start of the child, the next line is where Replay::SinceStart begins
+    pub fn child_started(&mut self) {
+        self.started = Some(self.offset + self.buffers.len());
+    }
+
     pub fn push(&mut self, line: HistoryLine) {
-        // TODO make asynchronous
-        let _: Vec<()> = self
-            .logfiles
-            .iter()
-            .map(|mut file| {
-                match line {
-                    HistoryLine::Child { ref message } => {
-                        file.write(message.as_slice()).unwrap();
-                    } //_ => (),
+        for file in &mut self.logfiles {
+            match line {
+                HistoryLine::Child { ref message } => file.write(message.clone()),
+                HistoryLine::Info { ref message } => {
+                    file.write(format!("{}\r\n", message).into_bytes())
                 }
-            })
-            .collect();
-        if self.buffers.len() >= self.histsize {
-            self.buffers.pop_front();
+            }
+        }
+        if let Some(ref mut histfile) = self.histfile {
+            histfile.write(line.encode());
+        }
+        self.store(line);
+    }
+
+    // Adds a line to the buffers
+    fn store(&mut self, line: HistoryLine) {
+        let bytes = line.bytes();
+        self.bytes += bytes;
+        self.lines += line.lines();
+        let starts_line = self.started == Some(self.offset + self.buffers.len());
+        let merged = match (self.buffers.back_mut(), &line) {
+            (Some(HistoryLine::Child { message: last }), HistoryLine::Child { message })
+                if last.len() + message.len() <= CHUNK_SIZE && !starts_line =>
+            {
+                last.extend_from_slice(message);
+                true
+            }
+            _ => false,
+        };
+        if !merged {
+            self.buffers.push_back(line);
+            self.starts.push_back(self.total);
+        }
+        self.total += bytes;
+        // Drop the oldest chunks until the history fits, the newest one is always kept
+        while self.buffers.len() > 1 && self.too_big() {
+            let old = self.buffers.pop_front().unwrap();
+            self.starts.pop_front();
+            self.bytes -= old.bytes();
+            self.lines -= old.lines();
+            self.cut = match old {
+                HistoryLine::Child { message } => !message.ends_with(b"\n"),
+                HistoryLine::Info { .. } => false,
+            };
             self.offset += 1;
         }
-        self.buffers.push_back(line);
         trace!("buffers are now: {:?}", self.buffers);
     }
 
-    pub fn get_from(&self, index: usize) -> Skip<Iter<HistoryLine>> {
-        let idx = if index < self.offset {
-            0
-        } else {
-            index - self.offset
-        };
-        self.buffers.iter().skip(idx)
+    fn too_big(&self) -> bool {
+        self.bytes > self.max_bytes || self.max_lines.is_some_and(|max| self.lines > max)
     }
 
-    // offset is the index a reader should start from
-    pub fn get_offset(&self) -> usize {
-        self.offset
+    // Where a new reader starts, never in the middle of a line
+    pub fn start(&self, replay: Replay) -> (usize, usize) {
+        match replay {
+            Replay::All => self.last_lines(self.max_lines),
+            Replay::Lines(lines) => {
+                self.last_lines(Some(self.max_lines.map_or(lines, |max| max.min(lines))))
+            }
+            Replay::Nothing => self.position(self.buffers.len(), 0),
+            Replay::SinceStart => match self.started {
+                Some(started) if started >= self.offset => (started, 0),
+                _ => self.last_lines(self.max_lines),
+            },
+        }
     }
-}
 
-pub struct HistoryWriter {
-    history: Rc<RefCell<History>>,
-}
+    // Position of the oldest line that is at most max_lines lines back
+    fn last_lines(&self, max_lines: Option<usize>) -> (usize, usize) {
+        if let Some(max) = max_lines {
+            let mut lines = 0;
+            for (i, entry) in self.buffers.iter().enumerate().rev() {
+                match entry {
+                    HistoryLine::Child { message } => {
+                        let newlines = message
+                            .iter()
+                            .enumerate()
+                            .rev()
+                            .filter(|&(_, &b)| b == b'\n');
+                        for (k, _) in newlines {
+                            lines += 1;
+                            if lines > max {
+                                return self.position(i, k + 1);
+                            }
+                        }
+                    }
+                    HistoryLine::Info { .. } => {
+                        lines += 1;
+                        if lines > max {
+                            return self.position(i + 1, 0);
+                        }
+                    }
+                }
+            }
+        }
+        if !self.cut {
+            return (self.offset, 0);
+        }
+        // Skip the rest of the cut line
+        for (i, entry) in self.buffers.iter().enumerate() {
+            match entry {
+                HistoryLine::Child { message } => {
+                    if let Some(k) = message.iter().position(|&b| b == b'\n') {
+                        return self.position(i, k + 1);
+                    }
+                }
+                HistoryLine::Info { .. } => return self.position(i, 0),
+            }
+        }
+        self.position(self.buffers.len(), 0)
+    }
 
-impl HistoryWriter {
-    pub fn new(history: Rc<RefCell<History>>) -> HistoryWriter {
-        HistoryWriter { history: history }
+    // Turns an index into the buffers into a reader position. The end of the history is kept on
+    // the last line, output might still be merged into it.
+    fn position(&self, index: usize, pos: usize) -> (usize, usize) {
+        match self.buffers.back() {
+            Some(last) if index == self.buffers.len() => (self.offset + index - 1, last.end()),
+            _ => (self.offset + index, pos),
+        }
     }
-}
 
-impl Sink for HistoryWriter {
-    type SinkItem = Vec<u8>;
-    type SinkError = io::Error;
+    // Where a reader that is too far behind continues: the oldest line that starts at most
+    // max_behind bytes before the end
+    pub fn skip_position(&self, max_behind: u64) -> (usize, usize) {
+        for (i, (entry, &start)) in self.buffers.iter().zip(&self.starts).enumerate() {
+            if self.total - start - entry.bytes() > max_behind {
+                continue;
+            }
+            match entry {
+                HistoryLine::Child { message } => {
+                    let newlines = message.iter().enumerate().filter(|&(_, &b)| b == b'\n');
+                    for (k, _) in newlines {
+                        if self.total - (start + k as u64 + 1) <= max_behind {
+                            return self.position(i, k + 1);
+                        }
+                    }
+                }
+                HistoryLine::Info { .. } => {
+                    if self.total - start <= max_behind {
+                        return self.position(i, 0);
+                    }
+                }
+            }
+        }
+        self.position(self.buffers.len(), 0)
+    }
 
-    fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, Self::SinkError> {
-        let mut history = self.history.borrow_mut();
-        history.push(HistoryLine::Child { message: item });
-        history.unpark();
-        Ok(AsyncSink::Ready)
+    // Converts a reader position into a position in all output so far
+    fn byte_position(&self, index: usize, pos: usize) -> u64 {
+        let i = index - self.offset;
+        match self.buffers.get(i) {
+            Some(HistoryLine::Child { .. }) => self.starts[i] + pos as u64,
+            Some(HistoryLine::Info { message }) if pos > 0 => self.starts[i] + message.len() as u64,
+            Some(HistoryLine::Info { .. }) => self.starts[i],
+            None => self.total,
+        }
     }
-    fn poll_complete(&mut self) -> Poll<(), Self::SinkError> {
-        Ok(Async::Ready(()))
+
+    pub fn get_from(&self, index: usize) -> Skip<Iter<'_, HistoryLine>> {
+        let idx = index.saturating_sub(self.offset);
+        self.buffers.iter().skip(idx)
+    }
+
+    // offset is the index a reader should start from
+    pub fn get_offset(&self) -> usize {
+        self.offset
     }
 }
 
 pub struct HistoryReader {
     history: Rc<RefCell<History>>,
+    // The line being read and the position in it
     index: usize,
+    pos: usize,
     first: bool,
+    // Whether info lines are included
+    info: bool,
+    replay: Replay,
+    // Set to replay the history again
+    rewind: Rc<Cell<Option<Replay>>>,
+    // Bytes of all output the reader is past, shared to tell its lag
+    read: Rc<Cell<u64>>,
+    limit: Option<(u64, SlowClient)>,
+    closed: bool,
+}
+
+/// Tells how far a reader is behind the newest output.
+#[derive(Clone)]
+pub struct Lag {
+    history: Rc<RefCell<History>>,
+    read: Rc<Cell<u64>>,
+}
+
+impl Lag {
+    pub fn bytes(&self) -> u64 {
+        self.history.borrow().total.saturating_sub(self.read.get())
+    }
 }
 
 impl HistoryReader {
-    pub fn new(history: Rc<RefCell<History>>) -> HistoryReader {
+    pub fn new(history: Rc<RefCell<History>>, info: bool, replay: Replay) -> HistoryReader {
+        let read = Rc::new(Cell::new(history.borrow().total));
         HistoryReader {
-            history: history,
+            history,
             index: 0,
+            pos: 0,
             first: true,
+            info,
+            replay,
+            rewind: Rc::new(Cell::new(None)),
+            read,
+            limit: None,
+            closed: false,
         }
     }
+
+    // Limits how many bytes the reader may fall behind before the policy kicks in
+    pub fn set_queue_limit(&mut self, max_behind: u64, policy: SlowClient) {
+        self.limit = Some((max_behind, policy));
+    }
+
+    pub fn lag(&self) -> Lag {
+        Lag {
+            history: self.history.clone(),
+            read: self.read.clone(),
+        }
+    }
+
+    // Setting the returned cell makes the reader start over, the history has to be unparked
+    pub fn rewinder(&self) -> Rc<Cell<Option<Replay>>> {
+        self.rewind.clone()
+    }
 }
 
 impl Stream for HistoryReader {
     type Item = Vec<u8>;
-    type Error = io::Error;
 
-    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
-        let mut history = self.history.borrow_mut();
-        if self.first {
-            self.first = false;
-            self.index = history.get_offset();
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.closed {
+            return Poll::Ready(None);
+        }
+        let mut history = this.history.borrow_mut();
+        let replay = if this.first {
+            Some(this.replay)
+        } else {
+            this.rewind.take()
+        };
+        if let Some(replay) = replay {
+            this.first = false;
+            let (index, pos) = history.start(replay);
+            this.index = index;
+            this.pos = pos;
+            this.read.set(history.byte_position(index, pos));
         }
         let mut res = Vec::new();
-        for entry in history.get_from(self.index) {
+        // Output the reader has not seen yet might have been dropped from the history already
+        let dropped = this.index < history.get_offset();
+        let behind = history.total - this.read.get();
+        if dropped || this.limit.is_some_and(|(max, _)| behind > max) {
+            if let Some((_, SlowClient::Disconnect)) = this.limit {
+                debug!("Reader is {} bytes behind, disconnecting", behind);
+                this.closed = true;
+                let message = "\r\n\x1B[33m[Too far behind, disconnecting]\x1B[0m\r\n";
+                return Poll::Ready(Some(message.as_bytes().to_vec()));
+            }
+            // Keep some of the newest output so the client has context
+            let max_behind = this.limit.map_or(u64::MAX, |(max, _)| max / 2);
+            let (index, pos) = history.skip_position(max_behind);
+            let read = history.byte_position(index, pos);
+            debug!("Reader is {} bytes behind, skipping", behind);
+            let message = format!(
+                "\r\n\x1B[33m[{} bytes skipped]\x1B[0m\r\n",
+                read - this.read.get()
+            );
+            res.extend_from_slice(message.as_bytes());
+            this.index = index;
+            this.pos = pos;
+            this.read.set(read);
+        }
+        let mut read = this.read.get();
+        let mut entries = history.get_from(this.index).peekable();
+        while res.len() < READ_SIZE {
+            let entry = match entries.next() {
+                Some(entry) => entry,
+                None => break,
+            };
             match entry {
-                &HistoryLine::Child { ref message } => {
-                    let mut content = message.clone();
-                    res.append(&mut content);
+                HistoryLine::Child { message } => {
+                    let end = cmp::min(message.len(), this.pos + READ_SIZE - res.len());
+                    res.extend_from_slice(&message[this.pos..end]);
+                    read += (end - this.pos) as u64;
+                    // The rest is sent on the next poll
+                    if end < message.len() {
+                        this.pos = end;
+                        break;
+                    }
+                }
+                HistoryLine::Info { message } if this.pos == 0 => {
+                    if this.info {
+                        let line = format!("\x1B[33m{}\x1B[0m\r\n", message);
+                        res.extend_from_slice(line.as_bytes());
+                    }
+                    read += message.len() as u64;
                 }
+                HistoryLine::Info { .. } => (),
                 //&HistoryLine::Command(ref cmd) => {
                 //    let mut content = cmd.clone();
                 //    res.append(&mut content);
                 //},
                 //e => println!("unkonwn entry {:?}", e),
             }
-            self.index = self.index + 1;
+            if entries.peek().is_some() {
+                this.index += 1;
+                this.pos = 0;
+            } else {
+                this.pos = entry.end();
+            }
         }
-        if res.len() > 0 {
-            Ok(Async::Ready(Some(res)))
+        this.read.set(read);
+        if !res.is_empty() {
+            Poll::Ready(Some(res))
         } else {
-            let task = task::current();
-            history.park(task);
-            Ok(Async::NotReady)
+            history.park(cx.waker().clone());
+            Poll::Pending
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tempdir;
+    use futures::{FutureExt, StreamExt};
+
+    fn history(max_bytes: u64, max_lines: Option<usize>) -> Rc<RefCell<History>> {
+        let options = Options {
+            history_size: max_bytes,
+            history_lines: max_lines,
+            ..Default::default()
+        };
+        Rc::new(RefCell::new(History::new(&options)))
+    }
+
+    fn push(history: &Rc<RefCell<History>>, data: &str) {
+        let message = data.as_bytes().to_vec();
+        history.borrow_mut().push(HistoryLine::Child { message });
+    }
+
+    fn read(reader: &mut HistoryReader) -> String {
+        match reader.next().now_or_never() {
+            Some(Some(data)) => String::from_utf8(data).unwrap(),
+            _ => String::new(),
+        }
+    }
+
+    #[test]
+    fn merges_small_chunks() {
+        let history = history(1 << 20, None);
+        let mut reader = HistoryReader::new(history.clone(), true, Replay::All);
+        push(&history, "a");
+        assert_eq!(read(&mut reader), "a");
+        push(&history, "b\r\n");
+        assert_eq!(history.borrow().buffers.len(), 1);
+        assert_eq!(read(&mut reader), "b\r\n");
+        assert_eq!(
+            read(&mut HistoryReader::new(history.clone(), true, Replay::All)),
+            "ab\r\n"
+        );
+    }
+
+    #[test]
+    fn drops_old_output_and_replays_whole_lines() {
+        let history = history(6000, None);
+        push(
+            &history,
+            &format!("{}\r\n{}", "a".repeat(3000), "b".repeat(1000)),
+        );
+        push(&history, &format!("{}\r\nc\r\n", "b".repeat(1000)));
+        push(&history, &"d".repeat(2000));
+        assert_eq!(history.borrow().bytes, 3005);
+        let replay = read(&mut HistoryReader::new(history.clone(), true, Replay::All));
+        assert_eq!(replay, format!("c\r\n{}", "d".repeat(2000)));
+    }
+
+    #[test]
+    fn replays_the_last_lines() {
+        let history = history(1 << 20, Some(2));
+        push(&history, "one\r\ntwo\r\n");
+        history.borrow_mut().event("event".to_owned());
+        push(&history, "three\r\nfour");
+        let replay = read(&mut HistoryReader::new(history.clone(), true, Replay::All));
+        assert_eq!(replay, "\x1B[33mevent\x1B[0m\r\nthree\r\nfour");
+    }
+
+    #[test]
+    fn replays_since_start_or_nothing() {
+        let history = history(1 << 20, None);
+        push(&history, "first run\r\n");
+        history.borrow_mut().child_started();
+        push(&history, "second run\r\n");
+        let mut reader = HistoryReader::new(history.clone(), true, Replay::SinceStart);
+        assert_eq!(read(&mut reader), "second run\r\n");
+        let mut reader = HistoryReader::new(history.clone(), true, Replay::Nothing);
+        assert_eq!(read(&mut reader), "");
+        push(&history, "more\r\n");
+        assert_eq!(read(&mut reader), "more\r\n");
+        reader.rewinder().set(Some(Replay::All));
+        assert_eq!(read(&mut reader), "first run\r\nsecond run\r\nmore\r\n");
+    }
+
+    #[test]
+    fn skips_output_for_slow_readers() {
+        let history = history(1 << 20, None);
+        let mut reader = HistoryReader::new(history.clone(), true, Replay::All);
+        reader.set_queue_limit(100, SlowClient::Skip);
+        let lag = reader.lag();
+        assert_eq!(read(&mut reader), "");
+        for i in 0..30 {
+            push(&history, &format!("line {:04}\n", i));
+        }
+        assert_eq!(lag.bytes(), 300);
+        let expected: String = (25..30).map(|i| format!("line {:04}\n", i)).collect();
+        assert_eq!(
+            read(&mut reader),
+            format!("\r\n\x1B[33m[250 bytes skipped]\x1B[0m\r\n{}", expected)
+        );
+        assert_eq!(lag.bytes(), 0);
+    }
+
+    #[test]
+    fn disconnects_slow_readers() {
+        let history = history(1 << 20, None);
+        let mut reader = HistoryReader::new(history.clone(), true, Replay::All);
+        reader.set_queue_limit(100, SlowClient::Disconnect);
+        assert_eq!(read(&mut reader), "");
+        push(&history, &"x".repeat(101));
+        assert!(read(&mut reader).contains("disconnecting"));
+        assert_eq!(reader.next().now_or_never(), Some(None));
+    }
+
+    #[test]
+    fn restores_the_history_file() {
+        let dir = tempdir("hist");
+        let path = dir.join("history");
+        let options = Options {
+            histfile: Some(path.clone()),
+            ..Default::default()
+        };
+        let history = Rc::new(RefCell::new(History::new(&options)));
+        push(&history, "before\r\n");
+        history.borrow_mut().event("Launched sh".to_owned());
+        // Waits for the histfile to be written
+        drop(history);
+
+        let history = Rc::new(RefCell::new(History::new(&options)));
+        push(&history, "after\r\n");
+        let replay = read(&mut HistoryReader::new(history.clone(), true, Replay::All));
+        assert_eq!(
+            replay,
+            "before\r\n\x1B[33mLaunched sh\x1B[0m\r\n\
+             \x1B[33mHistory from before the restart of the server ends here\x1B[0m\r\n\
+             after\r\n"
+        );
+    }
+
+    #[test]
+    fn truncates_a_broken_history_file() {
+        let dir = tempdir("broken");
+        let path = dir.join("history");
+        let good = HistoryLine::Child {
+            message: b"good\r\n".to_vec(),
+        };
+        let mut data = good.encode();
+        // A record cut short after its tag and length
+        data.extend_from_slice(&[RECORD_CHILD, 0, 0, 0, 10]);
+        fs::write(&path, &data).unwrap();
+
+        let options = Options {
+            histfile: Some(path.clone()),
+            ..Default::default()
+        };
+        let history = Rc::new(RefCell::new(History::new(&options)));
+        push(&history, "new\r\n");
+        drop(history);
+
+        let data = fs::read(&path).unwrap();
+        let (lines, valid) = HistoryLine::decode(&data);
+        assert_eq!(valid, data.len());
+        assert_eq!(lines[0], good);
+        assert_eq!(
+            lines.last(),
+            Some(&HistoryLine::Child {
+                message: b"new\r\n".to_vec()
+            })
+        );
+    }
+}
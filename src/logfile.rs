@@ -0,0 +1,373 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::options::Options;
+
+/// When a log file is rotated and how many old files are kept.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rotation {
+    pub max_size: Option<u64>,
+    pub max_age: Option<Duration>,
+    pub keep: usize,
+}
+
+/// A log file that is appended to. Old content is moved to `path.1`, `path.2`, ... when the file
+/// is rotated.
+pub struct LogFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    opened_at: Instant,
+    timestamps: bool,
+    // Whether the next byte starts a new line and needs a timestamp
+    line_start: bool,
+    rotation: Rotation,
+}
+
+impl LogFile {
+    pub fn open(path: &Path, options: &Options) -> io::Result<LogFile> {
+        LogFile::new(path, options.logtimestamps, options.logrotation.clone())
+    }
+
+    pub fn new(path: &Path, timestamps: bool, rotation: Rotation) -> io::Result<LogFile> {
+        let file = open_append(path)?;
+        let size = file.metadata()?.len();
+        Ok(LogFile {
+            path: path.to_owned(),
+            file,
+            size,
+            opened_at: Instant::now(),
+            timestamps,
+            line_start: true,
+            rotation,
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.should_rotate() {
+            self.rotate()?;
+        }
+        if !self.timestamps {
+            return self.append(data);
+        }
+        let mut buf = Vec::with_capacity(data.len() + 32);
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            if self.line_start {
+                buf.extend_from_slice(timestamp().as_bytes());
+            }
+            buf.extend_from_slice(line);
+            self.line_start = line.ends_with(b"\n");
+        }
+        self.append(&buf)
+    }
+
+    // Writes all of `data` or nothing, a failed write is cut off again. Otherwise the history
+    // file would be left with a partial record that the following ones are appended to.
+    fn append(&mut self, data: &[u8]) -> io::Result<()> {
+        let start = self.file.metadata()?.len();
+        match self.file.write_all(data) {
+            Ok(()) => {
+                self.size = start + data.len() as u64;
+                Ok(())
+            }
+            Err(e) => {
+                if let Err(e) = self.file.set_len(start) {
+                    debug!("Failed to truncate {}: {}", self.path.display(), e);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+
+    /// Opens the file again, for when it has been moved away by logrotate.
+    pub fn reopen(&mut self) -> io::Result<()> {
+        self.file = open_append(&self.path)?;
+        self.size = self.file.metadata()?.len();
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+
+    fn should_rotate(&self) -> bool {
+        if self.size == 0 {
+            return false;
+        }
+        let too_big = self.rotation.max_size.is_some_and(|max| self.size >= max);
+        let too_old = self
+            .rotation
+            .max_age
+            .is_some_and(|max| self.opened_at.elapsed() >= max);
+        too_big || too_old
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        debug!("Rotating {}", self.path.display());
+        if self.rotation.keep == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(numbered(&self.path, self.rotation.keep));
+            for n in (1..self.rotation.keep).rev() {
+                let from = numbered(&self.path, n);
+                if from.exists() {
+                    fs::rename(&from, numbered(&self.path, n + 1))?;
+                }
+            }
+            fs::rename(&self.path, numbered(&self.path, 1))?;
+        }
+        self.reopen()
+    }
+}
+
+// Number of writes that may be queued for a logfile before output is dropped
+const QUEUE_SIZE: usize = 1024;
+// How often writing to a broken logfile is retried
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+// How long to wait for queued writes when the server exits
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Writes to a `LogFile` on a thread of its own, so a full disk or a slow mount never blocks the
+/// event loop. Problems are reported as messages on `events`.
+pub struct LogWriter {
+    path: PathBuf,
+    tx: Option<SyncSender<Vec<u8>>>,
+    reopen: Arc<AtomicBool>,
+    done: mpsc::Receiver<()>,
+    // Bytes dropped because the queue was full
+    dropped: u64,
+    events: UnboundedSender<String>,
+}
+
+impl LogWriter {
+    pub fn new(file: LogFile, events: UnboundedSender<String>) -> LogWriter {
+        let (tx, rx) = mpsc::sync_channel(QUEUE_SIZE);
+        let (done_tx, done) = mpsc::channel();
+        let reopen = Arc::new(AtomicBool::new(false));
+        let path = file.path().to_owned();
+        let mut worker = Worker {
+            file,
+            retry_at: None,
+            lost: 0,
+            reopen: reopen.clone(),
+            events: events.clone(),
+        };
+        thread::spawn(move || {
+            worker.run(rx);
+            let _ = done_tx.send(());
+        });
+        LogWriter {
+            path,
+            tx: Some(tx),
+            reopen,
+            done,
+            dropped: 0,
+            events,
+        }
+    }
+
+    pub fn write(&mut self, data: Vec<u8>) {
+        let len = data.len() as u64;
+        let tx = self.tx.as_ref().expect("LogWriter used after drop");
+        match tx.try_send(data) {
+            Ok(()) if self.dropped > 0 => {
+                self.event(format!(
+                    "Logfile {} caught up, {} bytes were dropped",
+                    self.path.display(),
+                    self.dropped
+                ));
+                self.dropped = 0;
+            }
+            Ok(()) => (),
+            Err(TrySendError::Full(_)) => {
+                if self.dropped == 0 {
+                    self.event(format!(
+                        "Logfile {} can't keep up, dropping output",
+                        self.path.display()
+                    ));
+                }
+                self.dropped += len;
+            }
+            Err(TrySendError::Disconnected(_)) => (),
+        }
+    }
+
+    /// Makes the writer open the file again before the next write.
+    pub fn reopen(&self) {
+        self.reopen.store(true, Ordering::SeqCst);
+    }
+
+    fn event(&self, message: String) {
+        let _ = self.events.send(message);
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        // Closing the queue stops the thread once everything is written
+        self.tx.take();
+        if self.done.recv_timeout(FLUSH_TIMEOUT).is_err() {
+            warn!("Gave up writing to logfile {}", self.path.display());
+        }
+    }
+}
+
+struct Worker {
+    file: LogFile,
+    // When to try again after a failed write, None while the file is fine
+    retry_at: Option<Instant>,
+    // Bytes that could not be written since the file broke
+    lost: u64,
+    reopen: Arc<AtomicBool>,
+    events: UnboundedSender<String>,
+}
+
+impl Worker {
+    fn run(&mut self, rx: mpsc::Receiver<Vec<u8>>) {
+        for data in rx {
+            if self.reopen.swap(false, Ordering::SeqCst) {
+                self.reopen();
+            }
+            self.write(&data);
+        }
+    }
+
+    fn reopen(&mut self) {
+        match self.file.reopen() {
+            // Try to write right away if the file was broken
+            Ok(()) => self.retry_at = self.retry_at.map(|_| Instant::now()),
+            Err(e) => {
+                if self.retry_at.is_none() {
+                    self.event(format!(
+                        "Failed to reopen logfile {}: {}",
+                        self.file.path().display(),
+                        e
+                    ));
+                }
+                self.retry_at = Some(Instant::now() + RETRY_INTERVAL);
+            }
+        }
+    }
+
+    fn write(&mut self, data: &[u8]) {
+        if let Some(retry_at) = self.retry_at {
+            if Instant::now() < retry_at || self.file.reopen().is_err() {
+                self.lost += data.len() as u64;
+                return;
+            }
+        }
+        match self.file.write(data).and_then(|_| self.file.flush()) {
+            Ok(()) => {
+                if self.retry_at.take().is_some() {
+                    self.event(format!(
+                        "Logfile {} is writable again, {} bytes were lost",
+                        self.file.path().display(),
+                        self.lost
+                    ));
+                    self.lost = 0;
+                }
+            }
+            Err(e) => {
+                if self.retry_at.is_none() {
+                    self.event(format!(
+                        "Failed to write to logfile {}: {}, retrying every {}s",
+                        self.file.path().display(),
+                        e,
+                        RETRY_INTERVAL.as_secs()
+                    ));
+                }
+                self.retry_at = Some(Instant::now() + RETRY_INTERVAL);
+                self.lost += data.len() as u64;
+            }
+        }
+    }
+
+    fn event(&self, message: String) {
+        let _ = self.events.send(message);
+    }
+}
+
+fn open_append(path: &Path) -> io::Result<File> {
+    OpenOptions::new().append(true).create(true).open(path)
+}
+
+/// Path of the `n`th rotated file.
+pub fn numbered(path: &Path, n: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+fn timestamp() -> String {
+    time::strftime("%Y-%m-%d %H:%M:%S ", &time::now()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::tempdir;
+    use tokio::sync::mpsc;
+
+    #[test]
+    fn appends_and_timestamps_lines() {
+        let dir = tempdir("append");
+        let path = dir.join("ioc.log");
+        fs::write(&path, "old\n").unwrap();
+        let options = Options {
+            logtimestamps: true,
+            ..Default::default()
+        };
+        let mut log = LogFile::open(&path, &options).unwrap();
+        log.write(b"first\r\nsec").unwrap();
+        log.write(b"ond\r\n").unwrap();
+        let content = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = content.split('\n').collect();
+        assert_eq!(lines[0], "old");
+        assert!(lines[1].ends_with(" first\r"), "{:?}", lines[1]);
+        assert!(lines[2].ends_with(" second\r"), "{:?}", lines[2]);
+        assert_eq!(lines[1].len(), "2000-01-01 00:00:00 first\r".len());
+    }
+
+    #[test]
+    fn rotates_by_size_and_keeps_count() {
+        let dir = tempdir("rotate");
+        let path = dir.join("ioc.log");
+        let mut options = Options::default();
+        options.logrotation.max_size = Some(4);
+        options.logrotation.keep = 2;
+        let mut log = LogFile::open(&path, &options).unwrap();
+        for chunk in &["aaaa", "bbbb", "cccc", "dddd"] {
+            log.write(chunk.as_bytes()).unwrap();
+        }
+        assert_eq!(fs::read_to_string(&path).unwrap(), "dddd");
+        assert_eq!(fs::read_to_string(numbered(&path, 1)).unwrap(), "cccc");
+        assert_eq!(fs::read_to_string(numbered(&path, 2)).unwrap(), "bbbb");
+        assert!(!numbered(&path, 3).exists());
+    }
+
+    #[test]
+    fn writer_reports_write_errors() {
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        let file = LogFile::open(Path::new("/dev/full"), &Options::default()).unwrap();
+        let mut writer = LogWriter::new(file, tx);
+        writer.write(b"lost\r\n".to_vec());
+        writer.write(b"lost too\r\n".to_vec());
+        drop(writer);
+        let events: Vec<String> = std::iter::from_fn(|| rx.try_recv().ok()).collect();
+        assert_eq!(events.len(), 1, "{:?}", events);
+        assert!(events[0].starts_with("Failed to write to logfile /dev/full"));
+    }
+}
@@ -0,0 +1,103 @@
+use std::ffi::CString;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use log::{self, LogLevel, LogLevelFilter, LogMetadata, LogRecord};
+
+use crate::options::Options;
+
+const IDENT: &[u8] = b"rups\0";
+const FORMAT: &[u8] = b"%s\0";
+
+// The server log lives outside of the logger so it can be reopened on SIGHUP
+static SERVERLOG: Mutex<Option<(PathBuf, File)>> = Mutex::new(None);
+
+// Server messages go to stdout unless --quiet is given, and optionally to a file and syslog
+struct Logger {
+    level: LogLevelFilter,
+    stdout: bool,
+    syslog: bool,
+}
+
+impl log::Log for Logger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &LogRecord) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let message = match record.level() {
+            LogLevel::Info => format!("{}", record.args()),
+            LogLevel::Warn | LogLevel::Error => format!("{}: {}", record.level(), record.args()),
+            _ => format!("{} {}: {}", record.level(), record.target(), record.args()),
+        };
+        if self.stdout {
+            println!("{}", message);
+        }
+        if let Some((_, ref mut file)) = *SERVERLOG.lock().unwrap() {
+            let now = time::strftime("%Y-%m-%d %H:%M:%S", &time::now()).unwrap_or_default();
+            let _ = writeln!(file, "{} {}", now, message);
+        }
+        if self.syslog {
+            if let Ok(message) = CString::new(message) {
+                unsafe {
+                    libc::syslog(
+                        priority(record.level()),
+                        FORMAT.as_ptr() as *const _,
+                        message.as_ptr(),
+                    )
+                };
+            }
+        }
+    }
+}
+
+fn priority(level: LogLevel) -> libc::c_int {
+    match level {
+        LogLevel::Error => libc::LOG_ERR,
+        LogLevel::Warn => libc::LOG_WARNING,
+        LogLevel::Info => libc::LOG_INFO,
+        LogLevel::Debug | LogLevel::Trace => libc::LOG_DEBUG,
+    }
+}
+
+/// Installs the logger for server messages as configured by `options`.
+pub fn init(options: &Options) -> Result<(), String> {
+    if let Some(ref path) = options.serverlog {
+        *SERVERLOG.lock().unwrap() = Some((path.clone(), open(path)?));
+    }
+    if options.syslog {
+        // syslog keeps the pointer, the identity has to live forever
+        unsafe { libc::openlog(IDENT.as_ptr() as *const _, libc::LOG_PID, libc::LOG_DAEMON) };
+    }
+    let logger = Logger {
+        level: options.log_level,
+        stdout: !options.quiet,
+        syslog: options.syslog,
+    };
+    log::set_logger(|max_level| {
+        max_level.set(logger.level);
+        Box::new(logger)
+    })
+    .map_err(|e| e.to_string())
+}
+
+/// Opens the server log again, for when it has been moved away by logrotate.
+pub fn reopen() -> Result<(), String> {
+    if let Some((ref path, ref mut file)) = *SERVERLOG.lock().unwrap() {
+        *file = open(path)?;
+    }
+    Ok(())
+}
+
+fn open(path: &Path) -> Result<File, String> {
+    OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .map_err(|e| format!("Failed to open {}: {}", path.display(), e))
+}
@@ -1,50 +1,37 @@
 #[macro_use]
 extern crate log;
-extern crate env_logger;
 #[macro_use]
 extern crate clap;
-extern crate byteorder;
-extern crate fd;
-extern crate futures;
-extern crate futures_addition;
-extern crate libc;
-extern crate pty;
-extern crate rust_telnet;
-extern crate termios;
-extern crate time;
-extern crate tokio_core;
-extern crate tokio_file_unix;
-extern crate tokio_io;
-extern crate tokio_signal;
-extern crate tokio_timer;
 
 mod child;
+mod config;
 mod history;
+mod logfile;
+mod logger;
 mod options;
 mod telnet_server;
+mod users;
 mod util;
 
-use std::cell::RefCell;
-use std::fs::OpenOptions;
-use std::io;
+use std::cell::{Cell, RefCell};
+use std::os::unix::process::ExitStatusExt;
 use std::rc::Rc;
-use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use futures::{Future, Sink, Stream};
-use tokio_io::io::write_all;
-use tokio_signal::unix::Signal;
-
-use pty::PtyStreamError;
+use futures::StreamExt;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::task;
+use tokio::time::sleep;
 
 use termios::*;
 
-use child::{ProcessError, ProcessReaders};
-use history::*;
-use options::Options;
+use crate::child::{Event, ProcessError};
+use crate::history::*;
+use crate::options::Options;
+use crate::util::secs_to_duration;
 
 fn main() {
-    env_logger::init().unwrap();
     // Store the old termios settings, we might change them
     let mut termios = None;
     if unsafe { libc::isatty(libc::STDIN_FILENO) } == 1 {
@@ -53,172 +40,263 @@ fn main() {
 
     let options = Options::parse_args();
 
+    if let Err(e) = logger::init(&options) {
+        eprintln!("Failed to set up logging: {}", e);
+        std::process::exit(1);
+    }
+
     if options.binds.is_empty() && options.logbinds.is_empty() {
         panic!("No network binds!");
     }
 
-    run(options);
+    let exitcode = run(options);
 
     // Reset the termios after exiting
     if let Some(ref termios) = termios {
-        let _ = tcsetattr(libc::STDIN_FILENO, TCSANOW, termios).unwrap();
+        tcsetattr(libc::STDIN_FILENO, TCSANOW, termios).unwrap();
     }
+
+    std::process::exit(exitcode);
 }
 
-fn run(options: Options) {
-    let mut core = tokio_core::reactor::Core::new().unwrap();
-    let handle = core.handle();
+// Returns the exit code of the server, which reflects how the child exited
+fn run(options: Options) -> i32 {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    // The history and the options are shared with Rc, so everything runs on this thread
+    let local = task::LocalSet::new();
+    let exitcode = local.block_on(&runtime, serve(options));
+    // Dropping the tasks closes the logfiles
+    drop(local);
+    // Don't wait for a pending read of stdin
+    runtime.shutdown_background();
+    exitcode
+}
 
+async fn serve(options: Options) -> i32 {
     let options = Rc::new(RefCell::new(options));
     let history = Rc::new(RefCell::new(History::new(&options.borrow())));
 
-    let timer = tokio_timer::Timer::default();
+    let (child, mut events) = child::supervise(&options.borrow());
 
-    let mut child = child::Process::new(
-        options.borrow().command.clone(),
-        options.borrow().chdir.clone(),
-        history.clone(),
-        options.borrow().foreground,
-        core.handle(),
-    );
-    if options.borrow().autostart {
-        child.spawn().expect("Failed to start process");
+    // Bind before the child is started, so a taken address doesn't leave it behind
+    let mut telnet_server =
+        telnet_server::TelnetServer::new(history.clone(), child.clone(), options.clone());
+    {
+        let options = options.borrow();
+        let binds = options.binds.iter().map(|bind| (bind, false));
+        let logbinds = options.logbinds.iter().map(|bind| (bind, true));
+        for (bind, read_only) in binds.chain(logbinds) {
+            if let Err(e) = telnet_server.bind(bind, read_only) {
+                error!("Failed to listen on {}: {}", bind.addr, e);
+                telnet_server.remove_sockets();
+                return 1;
+            }
+        }
     }
-    let child = Arc::new(Mutex::new(child));
 
-    let terminate = Signal::new(libc::SIGINT, &handle);
-    let dead_children = Signal::new(libc::SIGCHLD, &handle);
+    // Set once a termination signal has been received, the child must not be restarted after that
+    let shutting_down = Rc::new(Cell::new(false));
 
     let holdoff = options.borrow().holdoff;
-    let sec = holdoff.floor();
-    let nsec = (holdoff - sec) * 1_000_000_000f64;
-
-    let sigchld_handling = dead_children
-        .and_then(|signal| {
-            signal
-                .fold(timer, |timer, signal| {
-                    trace!("got signal {:?}", signal);
-                    let child = child.clone();
-                    let child2 = child.clone();
-                    let mut child_locked = child.lock().unwrap();
-                    let pid = child_locked.id().unwrap();
-                    let exitcode = child_locked.wait().unwrap();
-                    println!("Received SIGCHLD for {}. {}", pid, exitcode);
-                    if options.borrow().autorestart {
-                        println!("Will restart in {:.2}s", holdoff);
-                        let timeout = timer
-                            .sleep(Duration::new(sec as u64, nsec as u32))
-                            .and_then(move |_| {
-                                match child2.lock().unwrap().spawn() {
-                                    Err(ProcessError::ProcessAlreadySpawned) => (),
-                                    Err(e) => println!("{:?}", e),
-                                    Ok(..) => (),
-                                }
-                                Ok(())
-                            })
-                            .map(|_| ())
-                            .map_err(|_| ());
-                        handle.spawn(timeout);
+
+    if options.borrow().autostart {
+        let child = child.clone();
+        let options = options.clone();
+        let shutting_down = shutting_down.clone();
+        task::spawn_local(async move {
+            if let Err(ProcessError::SpawnFailed(..)) = child.spawn().await {
+                if options.borrow().autorestart {
+                    child.report(format!("Will retry in {:.2}s", holdoff));
+                    let restart = restart_allowed(options.clone(), shutting_down);
+                    child::restart_later(child, holdoff, restart).await;
+                }
+            }
+        });
+    }
+
+    // Everything that happens to the child ends up in the history
+    {
+        let child = child.clone();
+        let history = history.clone();
+        let options = options.clone();
+        let shutting_down = shutting_down.clone();
+        let command = options.borrow().command[0].clone();
+        task::spawn_local(async move {
+            while let Some(event) = events.recv().await {
+                let mut history = history.borrow_mut();
+                match event {
+                    Event::Started { pid } => {
+                        history.child_started();
+                        history.event(format!("Launched {} (pid {})", command, pid));
+                    }
+                    Event::SpawnFailed(why) => {
+                        history.event(format!("Couldn't spawn {}: {}", command, why));
                     }
-                    let res: Result<tokio_timer::Timer, io::Error> = Ok(timer);
-                    res
-                })
-                .map(|_| ())
-        })
-        .map_err(|_| unimplemented!());
-
-    let sigint_handling = terminate
-        .and_then(|signal| {
-            signal.into_future().then(|_result| {
-                debug!("stahp ");
-                Ok(())
-            })
-        })
-        .map_err(|_| unimplemented!());
-
-    let child_readers = ProcessReaders::new(child.clone());
-    let proc_output = child_readers
-        .for_each(|reader| {
-            let hw = HistoryWriter::new(history.clone());
-            hw.send_all(reader.map_err(|e| match e {
-                PtyStreamError::IoError(e) => e,
-                _ => io::Error::new(io::ErrorKind::Other, "oops"),
-            }))
-            .map(|_| ())
-            .or_else(|_| Ok(()))
-        })
-        .map_err(|_| ());
+                    Event::Output(data) => {
+                        history.push(HistoryLine::Child { message: data });
+                        history.unpark();
+                    }
+                    Event::Exited { pid, status } => {
+                        history.event(format!("Received SIGCHLD for {}. {}", pid, status));
+                        if options.borrow().autorestart && !shutting_down.get() {
+                            history.event(format!("Will restart in {:.2}s", holdoff));
+                            let restart = restart_allowed(options.clone(), shutting_down.clone());
+                            task::spawn_local(child::restart_later(
+                                child.clone(),
+                                holdoff,
+                                restart,
+                            ));
+                        }
+                    }
+                    Event::Message(message) => history.event(message),
+                }
+            }
+        });
+    }
 
-    let mut telnet_server =
-        telnet_server::TelnetServer::new(history.clone(), child.clone(), options.clone());
-    for bind in options.borrow().binds.iter() {
-        telnet_server.bind(&bind, core.handle(), false);
+    {
+        let history = history.clone();
+        let mut events = history.borrow_mut().take_logfile_events().unwrap();
+        task::spawn_local(async move {
+            while let Some(message) = events.recv().await {
+                history.borrow_mut().event(message);
+            }
+        });
     }
-    for bind in options.borrow().logbinds.iter() {
-        telnet_server.bind(&bind, core.handle(), true);
+
+    // SIGHUP reopens the log files, so they can be rotated by an external tool. It does not shut
+    // the server down.
+    match signal(SignalKind::hangup()) {
+        Ok(mut hangups) => {
+            let history = history.clone();
+            task::spawn_local(async move {
+                while hangups.recv().await.is_some() {
+                    info!("Received SIGHUP, reopening log files");
+                    if let Err(e) = logger::reopen() {
+                        error!("Failed to reopen the server log: {}", e);
+                    }
+                    history.borrow_mut().reopen_logfiles();
+                }
+            });
+        }
+        Err(e) => error!("Failed to handle SIGHUP: {}", e),
     }
 
-    let mut joins = Vec::new();
+    // SIGUSR1 lists the connected clients and how far behind they are
+    match signal(SignalKind::user_defined1()) {
+        Ok(mut reports) => {
+            let clients = telnet_server.clients();
+            task::spawn_local(async move {
+                while reports.recv().await.is_some() {
+                    let clients = clients.borrow();
+                    info!("{} client(s) connected", clients.len());
+                    for (id, client) in clients.iter() {
+                        info!(
+                            "Client {} {}: {} bytes behind",
+                            id,
+                            client.peer,
+                            client.lag.bytes()
+                        );
+                    }
+                }
+            });
+        }
+        Err(e) => error!("Failed to handle SIGUSR1: {}", e),
+    }
 
     if options.borrow().foreground {
-        let hr = HistoryReader::new(history.clone());
-        //let stdout = std::io::stdout();
-        //let file = tokio_file_unix::StdFile(stdout.lock()); //Does not work because of borrow
-        //let file = tokio_file_unix::File::new_nb(file).unwrap();
-        let file = OpenOptions::new().write(true).open("/dev/stdout").unwrap();
-        let file = tokio_file_unix::File::new_nb(file).unwrap();
-        let file = file.into_io(&handle).unwrap();
-        let hr = hr
-            .fold(file, |file, msg| write_all(file, msg).map(|(file, _)| file))
-            .map(|_| ())
-            .map_err(|_| ());
-        joins.push(Box::new(hr) as Box<Future<Item = (), Error = ()>>);
+        // Events are printed by the server already
+        let mut hr = HistoryReader::new(history.clone(), false, Replay::All);
+        task::spawn_local(async move {
+            let mut stdout = tokio::io::stdout();
+            while let Some(data) = hr.next().await {
+                if stdout.write_all(&data).await.is_err() || stdout.flush().await.is_err() {
+                    break;
+                }
+            }
+        });
     }
 
     if options.borrow().interactive {
         let old_termios = Termios::from_fd(libc::STDIN_FILENO).unwrap();
         let mut new_termios = old_termios;
         new_termios.c_lflag &= !(ICANON | ECHO);
-        let _ = tcsetattr(libc::STDIN_FILENO, TCSANOW, &mut new_termios);
-        //let stdin = std::io::stdin();
-        //let file = tokio_file_unix::StdFile(stdin.lock()); //Does not work because of borrow
-        let file = std::fs::File::open("/dev/stdin").unwrap();
-        let file = tokio_file_unix::File::new_nb(file).unwrap();
-        let file = file.into_io(&handle).unwrap();
+        let _ = tcsetattr(libc::STDIN_FILENO, TCSANOW, &new_termios);
         let tx = telnet_server.tx();
-        let hw = futures::future::loop_fn((file, tx), |(file, tx)| {
-            tokio_io::io::read(file, [0u8; 10]).and_then(|(file, buf, len)| {
-                if len == 0 {
-                    //return futures::future::Loop::Break(())
-                    unreachable!()
+        task::spawn_local(async move {
+            let mut stdin = tokio::io::stdin();
+            let mut buf = [0u8; 10];
+            while let Ok(len) = stdin.read(&mut buf).await {
+                if len == 0 || tx.send(buf[..len].to_vec()).await.is_err() {
+                    break;
                 }
-                tx.send(buf[0..len].to_vec())
-                    .and_then(|tx| {
-                        tx.flush()
-                            .and_then(|tx| Ok(futures::future::Loop::Continue((file, tx))))
-                    })
-                    .map_err(|_| io::Error::new(io::ErrorKind::Other, "mupp"))
-            })
-        })
-        .map_err(|_| ());
-        joins.push(Box::new(hw) as Box<Future<Item = (), Error = ()>>);
+            }
+        });
     }
 
-    let telnet_server = telnet_server.server(core.handle());
+    let clients = telnet_server.clients();
+    let sockets = telnet_server.sockets();
+    task::spawn_local(telnet_server.server());
 
-    joins.push(Box::new(sigchld_handling) as Box<Future<Item = (), Error = ()>>);
-    joins.push(Box::new(proc_output) as Box<Future<Item = (), Error = ()>>);
-    joins.push(telnet_server);
-
-    let join = futures::future::join_all(joins).map(|_| ());
+    let (mut term, mut int) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(term), Ok(int)) => (term, int),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Failed to install signal handlers: {}", e);
+            return 1;
+        }
+    };
+    let signal = tokio::select! {
+        _ = term.recv() => libc::SIGTERM,
+        _ = int.recv() => libc::SIGINT,
+    };
+    shutting_down.set(true);
+    let message = format!("Received {}, shutting down", options::signal_name(signal));
+    history.borrow_mut().event(message.clone());
+    // Clients with --noinfo don't get events, but must not miss this one
+    if options.borrow().noinfo {
+        telnet_server::notify(&clients, &message);
+    }
+    // A second signal skips the grace period
+    let hurry = async move {
+        tokio::select! {
+            _ = term.recv() => (),
+            _ = int.recv() => (),
+        }
+    };
+    let stopsig = options.borrow().stopsig;
+    let stoptimeout = secs_to_duration(options.borrow().stoptimeout);
+    child::stop(child.clone(), stopsig, stoptimeout, hurry).await;
+    child::kill_leftovers(child.clone()).await;
+    // Give the clients a moment to receive the last output
+    sleep(Duration::from_millis(200)).await;
+    for socket in &sockets {
+        socket.remove();
+    }
+    info!("Done");
 
-    let select = futures::future::select_all(vec![
-        Box::new(join) as Box<Future<Item = (), Error = ()>>,
-        Box::new(sigint_handling) as Box<Future<Item = (), Error = ()>>,
-    ])
-    .map(|_| ());
+    let status = child
+        .status()
+        .await
+        .ok()
+        .and_then(|status| status.exit_status);
+    match status {
+        Some(status) => status
+            .code()
+            .unwrap_or_else(|| 128 + status.signal().unwrap_or(0)),
+        None => 0,
+    }
+}
 
-    match core.run(select) {
-        _ => println!("Done"),
-    };
+// Automatic restarts stop when they are toggled off or the server is shutting down
+fn restart_allowed(
+    options: Rc<RefCell<Options>>,
+    shutting_down: Rc<Cell<bool>>,
+) -> impl Fn() -> bool {
+    move || options.borrow().autorestart && !shutting_down.get()
 }
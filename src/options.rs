@@ -1,28 +1,263 @@
-use std::net::{IpAddr, SocketAddr};
-use std::path::PathBuf;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::time::Duration;
 
-use clap::{App, Arg};
-use time;
+use clap::{self, App, Arg};
+use log::LogLevelFilter;
 
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
+use crate::config;
+use crate::history::{Replay, SlowClient};
+use crate::logfile::Rotation;
+use crate::users;
+
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// An address the telnet server can listen on.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BindAddr {
+    Tcp(SocketAddr),
+    /// Path to a unix domain socket, access is controlled by the filesystem permissions.
+    Unix(PathBuf),
+}
+
+impl BindAddr {
+    /// Parses a bind argument. Accepted forms are `port` (localhost), `*:port` (all interfaces),
+    /// `ip:port`, `[ipv6]:port`, `hostname:port` and `unix:path`. A hostname can resolve to
+    /// several addresses, all of them are returned.
+    pub fn parse_all(s: &str) -> Result<Vec<BindAddr>, String> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(format!("Missing socket path in '{}'", s));
+            }
+            return Ok(vec![BindAddr::Unix(PathBuf::from(path))]);
+        }
+        if let Ok(port) = s.parse::<u16>() {
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+            return Ok(vec![BindAddr::Tcp(addr)]);
+        }
+        if let Ok(addr) = s.parse::<SocketAddr>() {
+            return Ok(vec![BindAddr::Tcp(addr)]);
+        }
+        let (host, port) = match s.rfind(':') {
+            Some(idx) => (&s[..idx], &s[idx + 1..]),
+            None => return Err(format!("Invalid address '{}': expected [host:]port", s)),
+        };
+        let port = port
+            .parse::<u16>()
+            .map_err(|_| format!("Invalid port '{}' in address '{}'", port, s))?;
+        if host == "*" {
+            let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
+            return Ok(vec![BindAddr::Tcp(addr)]);
+        }
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if host.is_empty() {
+            return Err(format!("Missing host in address '{}'", s));
+        }
+        let mut addrs = Vec::new();
+        let resolved = (host, port)
+            .to_socket_addrs()
+            .map_err(|e| format!("Failed to resolve '{}': {}", host, e))?;
+        for addr in resolved {
+            let addr = BindAddr::Tcp(addr);
+            if !addrs.contains(&addr) {
+                addrs.push(addr);
+            }
+        }
+        if addrs.is_empty() {
+            return Err(format!("'{}' did not resolve to any address", host));
+        }
+        Ok(addrs)
+    }
+}
+
+impl fmt::Display for BindAddr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BindAddr::Tcp(ref addr) => write!(f, "{}", addr),
+            BindAddr::Unix(ref path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+/// An address to listen on, how much history its clients are sent and whether they are asked
+/// for an 8-bit clean session.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bind {
+    pub addr: BindAddr,
+    // Falls back to --replay
+    pub replay: Option<Replay>,
+    // Negotiate telnet BINARY in both directions right away
+    pub binary: bool,
+}
+
+impl Bind {
+    /// Parses a bind argument, an address as accepted by `BindAddr::parse_all` that is optionally
+    /// followed by `,replay=MODE` and `,binary` in any order.
+    pub fn parse_all(s: &str) -> Result<Vec<Bind>, String> {
+        let mut addr = s;
+        let mut replay = None;
+        let mut binary = false;
+        loop {
+            if let Some(rest) = addr.strip_suffix(",binary") {
+                binary = true;
+                addr = rest;
+                continue;
+            }
+            match addr.rfind(",replay=") {
+                Some(idx) if replay.is_none() => {
+                    let mode = &addr[idx + ",replay=".len()..];
+                    replay = Some(
+                        parse_replay(mode)
+                            .ok_or_else(|| format!("Invalid replay mode '{}' in '{}'", mode, s))?,
+                    );
+                    addr = &addr[..idx];
+                }
+                _ => break,
+            }
+        }
+        let addrs = BindAddr::parse_all(addr)?;
+        Ok(addrs
+            .into_iter()
+            .map(|addr| Bind {
+                addr,
+                replay,
+                binary,
+            })
+            .collect())
+    }
+}
+
+impl fmt::Display for Bind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.addr)?;
+        if let Some(replay) = self.replay {
+            write!(f, ",replay={}", replay)?;
+        }
+        if self.binary {
+            write!(f, ",binary")?;
+        }
+        Ok(())
+    }
+}
+
+/// Changes to the environment the child inherits from the server. The environment is cleared
+/// first, then variables are unset and finally set, later settings win.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Environment {
+    pub clear: bool,
+    pub unset: Vec<String>,
+    pub set: Vec<(String, String)>,
+}
+
+impl Environment {
+    /// Parses `KEY=VALUE`.
+    pub fn parse_var(s: &str) -> Result<(String, String), String> {
+        match s.find('=') {
+            Some(idx) if idx > 0 => Ok((s[..idx].to_owned(), s[idx + 1..].to_owned())),
+            _ => Err(format!(
+                "Invalid environment variable '{}': expected KEY=VALUE",
+                s
+            )),
+        }
+    }
+
+    /// Reads `KEY=VALUE` lines from a file, empty lines and lines starting with # are skipped.
+    pub fn read_file(path: &Path) -> Result<Vec<(String, String)>, String> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("Failed to read env file {}: {}", path.display(), e))?;
+        let mut vars = Vec::new();
+        for (lineno, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let var = Environment::parse_var(line)
+                .map_err(|e| format!("{}:{}: {}", path.display(), lineno + 1, e))?;
+            vars.push(var);
+        }
+        Ok(vars)
+    }
+
+    /// Sets a variable, replacing an earlier value for the same key.
+    pub fn add_var(&mut self, var: (String, String)) {
+        self.set.retain(|(key, _)| *key != var.0);
+        self.set.push(var);
+    }
+
+    /// Removes a variable, including a value set for it earlier.
+    pub fn unset_var(&mut self, key: String) {
+        self.set.retain(|(k, _)| *k != key);
+        if !self.unset.contains(&key) {
+            self.unset.push(key);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        !self.clear && self.unset.is_empty() && self.set.is_empty()
+    }
+}
+
+impl fmt::Display for Environment {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if self.clear {
+            parts.push("cleared".to_owned());
+        }
+        if !self.unset.is_empty() {
+            parts.push(format!("unset {}", self.unset.join(" ")));
+        }
+        for (key, value) in &self.set {
+            parts.push(format!("{}={}", key, value));
+        }
+        write!(f, "{}", parts.join(", "))
+    }
+}
 
 pub struct Options {
     pub command: Vec<String>,
-    pub history_size: usize,
+    pub quiet: bool,
+    pub log_level: LogLevelFilter,
+    pub syslog: bool,
+    pub serverlog: Option<PathBuf>,
+    // Bytes of child output and messages to keep for new clients
+    pub history_size: u64,
+    pub history_lines: Option<usize>,
+    pub histfile: Option<PathBuf>,
     pub foreground: bool,
     pub interactive: bool,
     pub autostart: bool,
     pub autorestart: bool,
     pub noinfo: bool,
     pub holdoff: f64,
-    pub binds: Vec<SocketAddr>,
-    pub logbinds: Vec<SocketAddr>,
+    pub stopsig: libc::c_int,
+    pub stoptimeout: f64,
+    pub killsig: libc::c_int,
+    pub killtimeout: Option<f64>,
+    pub killgroup: bool,
+    pub env: Environment,
+    // TERM for the child, otherwise it is taken from the first control client
+    pub term: Option<String>,
+    pub user: Option<String>,
+    pub group: Option<String>,
+    // Resolved from user and group
+    pub credentials: Option<pty::Credentials>,
+    pub binds: Vec<Bind>,
+    pub logbinds: Vec<Bind>,
+    pub replay: Replay,
+    // Bytes a client may fall behind the newest output
+    pub client_queue: u64,
+    pub slow_clients: SlowClient,
     pub logfiles: Vec<PathBuf>,
+    pub logtimestamps: bool,
+    pub logrotation: Rotation,
     pub killcmd: Option<u8>,
     pub togglecmd: Option<u8>,
     pub restartcmd: Option<u8>,
     pub logoutcmd: Option<u8>,
+    pub replaycmd: Option<u8>,
     pub chdir: PathBuf,
     // Not really an option.. but lets store it here for now..
     pub started_at: String,
@@ -30,32 +265,64 @@ pub struct Options {
 
 impl Default for Options {
     fn default() -> Options {
-        let mut addrs = Vec::new();
-        addrs.push(SocketAddr::new(
-            IpAddr::from_str("127.0.0.1").unwrap(),
-            3000,
-        ));
-        let mut logaddrs = Vec::new();
-        logaddrs.push(SocketAddr::new(
-            IpAddr::from_str("127.0.0.1").unwrap(),
-            4000,
-        ));
+        let addrs = vec![Bind {
+            addr: BindAddr::Tcp(SocketAddr::new(
+                IpAddr::from_str("127.0.0.1").unwrap(),
+                3000,
+            )),
+            replay: None,
+            binary: false,
+        }];
+        let logaddrs = vec![Bind {
+            addr: BindAddr::Tcp(SocketAddr::new(
+                IpAddr::from_str("127.0.0.1").unwrap(),
+                4000,
+            )),
+            replay: None,
+            binary: false,
+        }];
         Options {
             command: Vec::new(),
-            history_size: 20_000,
+            quiet: false,
+            log_level: LogLevelFilter::Info,
+            syslog: false,
+            serverlog: None,
+            history_size: 1 << 20,
+            history_lines: None,
+            histfile: None,
             foreground: false,
             interactive: false,
             autostart: true,
             autorestart: true,
             noinfo: false,
             holdoff: 5.0,
+            stopsig: libc::SIGTERM,
+            stoptimeout: 5.0,
+            killsig: libc::SIGKILL,
+            killtimeout: None,
+            killgroup: false,
+            env: Environment::default(),
+            term: None,
+            user: None,
+            group: None,
+            credentials: None,
             binds: addrs,
             logbinds: logaddrs,
+            replay: Replay::All,
+            client_queue: 256 << 10,
+            slow_clients: SlowClient::Skip,
             logfiles: Vec::new(),
+            logtimestamps: false,
+            logrotation: Rotation {
+                max_size: None,
+                max_age: None,
+                keep: 5,
+            },
             killcmd: Some(0x18),
             togglecmd: Some(0x14),
             restartcmd: Some(0x12),
             logoutcmd: None,
+            replaycmd: None,
             chdir: ::std::env::current_dir().expect("Failed to get pwd"),
             started_at: time::strftime("%a, %d %b %Y %T %z", &time::now())
                 .expect("Failed to format time"),
@@ -87,6 +354,24 @@ impl Options {
                     .long("quiet")
                     .help("suppress messages (server)"),
             )
+            .arg(
+                Arg::with_name("log-level")
+                    .long("log-level")
+                    .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                    .help("Level of server messages (default info)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("syslog")
+                    .long("syslog")
+                    .help("send server messages to syslog"),
+            )
+            .arg(
+                Arg::with_name("serverlog")
+                    .long("serverlog")
+                    .help("Append timestamped server messages to a file")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::with_name("noinfo")
                     .short("n")
@@ -105,6 +390,86 @@ impl Options {
                     .help("wait n seconds between process restart")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("stopsig")
+                    .long("stopsig")
+                    .help("signal sent to the process when the server shuts down (default TERM)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("stoptimeout")
+                    .long("stoptimeout")
+                    .help("wait n seconds for the process to stop before sending SIGKILL")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("killsig")
+                    .long("killsig")
+                    .help("signal sent to the process by the kill command (default KILL)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("killtimeout")
+                    .long("killtimeout")
+                    .help("send SIGKILL if the process is alive n seconds after the kill command")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("killgroup")
+                    .long("killgroup")
+                    .help("send signals to the whole process group of the process"),
+            )
+            .arg(
+                Arg::with_name("env")
+                    .short("e")
+                    .long("env")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Set KEY=VALUE in the environment of the process")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("unset-env")
+                    .long("unset-env")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Remove KEY from the environment of the process")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("clear-env")
+                    .long("clear-env")
+                    .help("start the process with an empty environment"),
+            )
+            .arg(
+                Arg::with_name("env-file")
+                    .long("env-file")
+                    .help("Read KEY=VALUE lines for the environment of the process from a file")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("term")
+                    .long("term")
+                    .help(
+                        "TERM for the process, by default the terminal type of the first client \
+                         that may send input",
+                    )
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("user")
+                    .short("u")
+                    .long("user")
+                    .help("Run the process as this user (name or uid)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("group")
+                    .short("g")
+                    .long("group")
+                    .help("Run the process with this group (name or gid), default is the user's group")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::with_name("interactive")
                     .short("I")
@@ -116,7 +481,11 @@ impl Options {
                     .short("b")
                     .long("bind")
                     .multiple(true)
-                    .help("Bind to address (default is 127.0.0.1:3000")
+                    .help(
+                        "Bind to address, [host:]port, *:port or unix:path (default is \
+                         127.0.0.1:3000), add ,replay=MODE to override --replay and ,binary \
+                         for binary sessions",
+                    )
                     .takes_value(true),
             )
             .arg(
@@ -124,7 +493,10 @@ impl Options {
                     .short("l")
                     .long("logbind")
                     .multiple(true)
-                    .help("Bind to address for log output (ignore any received data)")
+                    .help(
+                        "Bind to address for log output, [host:]port, *:port or unix:path \
+                         (ignore any received data)",
+                    )
                     .takes_value(true),
             )
             .arg(
@@ -135,17 +507,52 @@ impl Options {
                     .help("Output to logfile")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("logtimestamps")
+                    .long("logtimestamps")
+                    .help("prefix every line in the logfiles with a timestamp"),
+            )
+            .arg(
+                Arg::with_name("logmaxsize")
+                    .long("logmaxsize")
+                    .help("Rotate logfiles when they reach this size, e.g. 500K, 10M or 1G")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("logmaxage")
+                    .long("logmaxage")
+                    .help("Rotate logfiles after this time, e.g. 3600, 30m, 12h or 7d")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("logkeep")
+                    .long("logkeep")
+                    .help("Number of rotated logfiles to keep (default 5)")
+                    .takes_value(true),
+            )
             .arg(
                 Arg::with_name("histsize")
                     .long("histsize")
-                    .help("Set maximum telnet packets to remember")
+                    .help("Bytes of output to remember for new clients, e.g. 500K (default 1M)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("histfile")
+                    .long("histfile")
+                    .help("Keep the history in this file to show it again after a restart")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("histlines")
+                    .long("histlines")
+                    .help("Maximum lines of output to remember for new clients")
                     .takes_value(true),
             )
             .arg(
                 Arg::with_name("killcmd")
                     .short("k")
                     .long("killcmd")
-                    .help("Command to send SIGKILL to process")
+                    .help("Command to send the kill signal to process")
                     .takes_value(true),
             )
             .arg(
@@ -168,6 +575,34 @@ impl Options {
                     .help("Command to logout client connection")
                     .takes_value(true),
             )
+            .arg(
+                Arg::with_name("replaycmd")
+                    .long("replaycmd")
+                    .help("Command to replay the history, asks for the replay mode")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("replay")
+                    .long("replay")
+                    .help(
+                        "History sent to new clients: all, none, start (since the process was \
+                         started) or a number of lines (default all)",
+                    )
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("clientqueue")
+                    .long("clientqueue")
+                    .help("Bytes a client may fall behind the output, e.g. 64K (default 256K)")
+                    .takes_value(true),
+            )
+            .arg(
+                Arg::with_name("slowclients")
+                    .long("slowclients")
+                    .help("What to do with clients that fall behind (default skip)")
+                    .possible_values(&["skip", "disconnect"])
+                    .takes_value(true),
+            )
             .arg(
                 Arg::with_name("chdir")
                     .short("c")
@@ -175,12 +610,22 @@ impl Options {
                     .help("Process working directory")
                     .takes_value(true),
             )
-            .arg(Arg::with_name("command").required(true).multiple(true))
+            .arg(
+                Arg::with_name("config")
+                    .long("config")
+                    .help("Read options from a TOML file, command line flags take precedence")
+                    .takes_value(true),
+            )
+            .arg(Arg::with_name("command").multiple(true))
             .after_help(
                 "All commands (killcmd, ...) take either a single \
                          letter or caret (^) + a single letter as arguments. \
                          For example '^x' for Ctrl-X or 'x' for literal x.
 
+SIGNALS:
+    SIGTERM and SIGINT stop the process and the server. SIGHUP does not stop \
+    anything, it reopens the log files. SIGUSR1 lists the connected clients.
+
 EXAMPLES:
     rups bash
 
@@ -189,84 +634,361 @@ EXAMPLES:
             )
             .get_matches();
 
-        options.command = matches
-            .values_of("command")
-            .expect("Argument 'command' missing")
-            .map(String::from)
-            .collect();
+        if let Some(path) = matches.value_of("config") {
+            if let Err(msg) = config::load(Path::new(path), &mut options) {
+                clap::Error::with_description(&msg, clap::ErrorKind::InvalidValue).exit();
+            }
+        }
 
-        options.foreground = matches.is_present("foreground");
-        options.autorestart = !matches.is_present("noautorestart");
-        options.autostart = !matches.is_present("wait");
-        options.interactive = matches.is_present("interactive");
-        options.noinfo = matches.is_present("noinfo");
+        if let Some(command) = matches.values_of("command") {
+            options.command = command.map(String::from).collect();
+        }
+        if options.command.is_empty() {
+            clap::Error::with_description(
+                "No command given, neither on the command line nor in the config file",
+                clap::ErrorKind::MissingRequiredArgument,
+            )
+            .exit();
+        }
+
+        if matches.is_present("quiet") {
+            options.quiet = true;
+        }
+        if let Some(level) = matches.value_of("log-level") {
+            options.log_level = parse_log_level(level).unwrap();
+        }
+        if matches.is_present("syslog") {
+            options.syslog = true;
+        }
+        if let Some(path) = matches.value_of("serverlog") {
+            options.serverlog = Some(PathBuf::from(path));
+        }
+        if matches.is_present("foreground") {
+            options.foreground = true;
+        }
+        if matches.is_present("noautorestart") {
+            options.autorestart = false;
+        }
+        if matches.is_present("wait") {
+            options.autostart = false;
+        }
+        if matches.is_present("interactive") {
+            options.interactive = true;
+        }
+        if matches.is_present("noinfo") {
+            options.noinfo = true;
+        }
+        if matches.is_present("killgroup") {
+            options.killgroup = true;
+        }
+        if matches.is_present("clear-env") {
+            options.env.clear = true;
+        }
+        // The config file is applied already, so the command line wins over it
+        if let Some(path) = matches.value_of("env-file") {
+            match Environment::read_file(Path::new(path)) {
+                Ok(vars) => vars.into_iter().for_each(|var| options.env.add_var(var)),
+                Err(msg) => {
+                    clap::Error::with_description(&msg, clap::ErrorKind::InvalidValue).exit()
+                }
+            }
+        }
+        if let Some(keys) = matches.values_of("unset-env") {
+            keys.for_each(|key| options.env.unset_var(key.to_owned()));
+        }
+        if let Some(vars) = matches.values_of("env") {
+            for var in vars {
+                match Environment::parse_var(var) {
+                    Ok(var) => options.env.add_var(var),
+                    Err(msg) => {
+                        clap::Error::with_description(&msg, clap::ErrorKind::InvalidValue).exit()
+                    }
+                }
+            }
+        }
 
         if let Ok(holdoff) = value_t!(matches, "holdoff", f64) {
             options.holdoff = holdoff;
         }
-        if let Ok(history_size) = value_t!(matches, "histsize", usize) {
-            options.history_size = history_size;
+        if let Some(sig) = matches.value_of("stopsig") {
+            options.stopsig = parse_signal_arg(sig);
+        }
+        if matches.is_present("stoptimeout") {
+            options.stoptimeout = value_t_or_exit!(matches, "stoptimeout", f64);
+        }
+        if let Some(sig) = matches.value_of("killsig") {
+            options.killsig = parse_signal_arg(sig);
+        }
+        if matches.is_present("killtimeout") {
+            options.killtimeout = Some(value_t_or_exit!(matches, "killtimeout", f64));
+        }
+        if let Some(size) = matches.value_of("histsize") {
+            match parse_size(size) {
+                Some(size) => options.history_size = size,
+                None => clap::Error::with_description(
+                    &format!("Invalid size '{}'", size),
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit(),
+            }
+        }
+        if let Some(path) = matches.value_of("histfile") {
+            options.histfile = Some(PathBuf::from(path));
+        }
+        if matches.is_present("histlines") {
+            options.history_lines = Some(value_t_or_exit!(matches, "histlines", usize));
         }
         if let Some(bindv) = matches.values_of("bind") {
-            // TODO(nc): Interpret ip:port, port, unix socket
-            options.binds = bindv.map(|b| b.parse().unwrap()).collect();
+            options.binds = parse_binds(bindv);
         }
         if let Some(bindv) = matches.values_of("logbind") {
-            // TODO(nc): Interpret ip:port, port, unix socket
-            options.logbinds = bindv.map(|b| b.parse().unwrap()).collect();
+            options.logbinds = parse_binds(bindv);
+        }
+        if let Some(replay) = matches.value_of("replay") {
+            match parse_replay(replay) {
+                Some(replay) => options.replay = replay,
+                None => clap::Error::with_description(
+                    &format!("Invalid replay mode '{}'", replay),
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit(),
+            }
+        }
+        if let Some(size) = matches.value_of("clientqueue") {
+            match parse_size(size) {
+                Some(size) => options.client_queue = size,
+                None => clap::Error::with_description(
+                    &format!("Invalid size '{}'", size),
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit(),
+            }
+        }
+        if let Some(policy) = matches.value_of("slowclients") {
+            options.slow_clients = parse_slow_clients(policy).unwrap();
         }
         if let Some(pathv) = matches.values_of("logfile") {
-            options.logfiles = pathv.map(|b| PathBuf::from(b)).collect();
+            options.logfiles = pathv.map(PathBuf::from).collect();
         }
-        if let Some(cmd) = matches.value_of("killcmd") {
-            match parse_shortcut(cmd.as_bytes()) {
-                Ok(cmd) => options.killcmd = cmd,
-                Err(..) => println!("Failed to parse {}", cmd),
+        if matches.is_present("logtimestamps") {
+            options.logtimestamps = true;
+        }
+        if let Some(size) = matches.value_of("logmaxsize") {
+            match parse_size(size) {
+                Some(size) => options.logrotation.max_size = Some(size),
+                None => clap::Error::with_description(
+                    &format!("Invalid size '{}'", size),
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit(),
             }
         }
-        if let Some(cmd) = matches.value_of("autorestartcmd") {
-            match parse_shortcut(cmd.as_bytes()) {
-                Ok(cmd) => options.togglecmd = cmd,
-                Err(..) => println!("Failed to parse {}", cmd),
+        if let Some(age) = matches.value_of("logmaxage") {
+            match parse_age(age) {
+                Some(age) => options.logrotation.max_age = Some(age),
+                None => clap::Error::with_description(
+                    &format!("Invalid time '{}'", age),
+                    clap::ErrorKind::InvalidValue,
+                )
+                .exit(),
             }
         }
+        if matches.is_present("logkeep") {
+            options.logrotation.keep = value_t_or_exit!(matches, "logkeep", usize);
+        }
+        if let Some(cmd) = matches.value_of("killcmd") {
+            options.killcmd = shortcut_arg(cmd, "killcmd");
+        }
+        if let Some(cmd) = matches.value_of("autorestartcmd") {
+            options.togglecmd = shortcut_arg(cmd, "autorestartcmd");
+        }
         if let Some(cmd) = matches.value_of("restartcmd") {
-            match parse_shortcut(cmd.as_bytes()) {
-                Ok(cmd) => options.restartcmd = cmd,
-                Err(..) => println!("Failed to parse {}", cmd),
-            }
+            options.restartcmd = shortcut_arg(cmd, "restartcmd");
         }
         if let Some(cmd) = matches.value_of("logoutcmd") {
-            match parse_shortcut(cmd.as_bytes()) {
-                Ok(cmd) => options.logoutcmd = cmd,
-                Err(..) => println!("Failed to parse {}", cmd),
-            }
+            options.logoutcmd = shortcut_arg(cmd, "logoutcmd");
+        }
+        if let Some(cmd) = matches.value_of("replaycmd") {
+            options.replaycmd = shortcut_arg(cmd, "replaycmd");
+        }
+        if let Some(term) = matches.value_of("term") {
+            options.term = Some(term.to_owned());
+        }
+        if let Some(user) = matches.value_of("user") {
+            options.user = Some(user.to_owned());
+        }
+        if let Some(group) = matches.value_of("group") {
+            options.group = Some(group.to_owned());
+        }
+        match users::lookup(options.user.as_deref(), options.group.as_deref()) {
+            Ok(credentials) => options.credentials = credentials,
+            Err(msg) => clap::Error::with_description(&msg, clap::ErrorKind::InvalidValue).exit(),
         }
         if let Some(chdir) = matches.value_of("chdir") {
-            let chdir = PathBuf::from(chdir);
-            if !chdir.is_dir() {
-                panic!("Process working directory must exist");
-            }
-            options.chdir = chdir;
+            options.chdir = PathBuf::from(chdir);
+        }
+        if !options.chdir.is_dir() {
+            panic!("Process working directory must exist");
         }
 
-        if options.killcmd == options.togglecmd
-            || options.killcmd == options.restartcmd
-            || options.togglecmd == options.restartcmd
-        {
-            panic!("It is not allowed to have the same shortcut for multiple commands");
+        if options.has_shortcut_collision() {
+            clap::Error::with_description(
+                "It is not allowed to have the same shortcut for multiple commands",
+                clap::ErrorKind::ArgumentConflict,
+            )
+            .exit();
         }
 
         options
     }
 
+    // Whether two commands share a shortcut, disabled commands don't count
+    fn has_shortcut_collision(&self) -> bool {
+        let mut shortcuts: Vec<u8> = [
+            self.killcmd,
+            self.togglecmd,
+            self.restartcmd,
+            self.logoutcmd,
+            self.replaycmd,
+        ]
+        .iter()
+        .flatten()
+        .cloned()
+        .collect();
+        let count = shortcuts.len();
+        shortcuts.sort_unstable();
+        shortcuts.dedup();
+        shortcuts.len() != count
+    }
+
     pub fn toggle_autorestart(&mut self) {
         self.autorestart = !self.autorestart;
     }
 }
 
+// Parses all bind arguments, exits with a usage error if any of them is invalid
+fn parse_binds<'a, I: Iterator<Item = &'a str>>(bindv: I) -> Vec<Bind> {
+    let mut binds = Vec::new();
+    for bind in bindv {
+        match Bind::parse_all(bind) {
+            Ok(mut addrs) => binds.append(&mut addrs),
+            Err(msg) => clap::Error::with_description(&msg, clap::ErrorKind::InvalidValue).exit(),
+        }
+    }
+    binds
+}
+
+// Parses the shortcut given for `arg`, exits with a usage error if it is invalid
+fn shortcut_arg(cmd: &str, arg: &str) -> Option<u8> {
+    parse_shortcut(cmd.as_bytes()).unwrap_or_else(|_| {
+        clap::Error::value_validation_auto(format!("Failed to parse '{}' for '--{}'", cmd, arg))
+            .exit()
+    })
+}
+
+// Parses a size in bytes with an optional K, M or G suffix
+pub fn parse_size(size: &str) -> Option<u64> {
+    let (num, unit) = match size.char_indices().last() {
+        Some((idx, 'K')) | Some((idx, 'k')) => (&size[..idx], 1 << 10),
+        Some((idx, 'M')) | Some((idx, 'm')) => (&size[..idx], 1 << 20),
+        Some((idx, 'G')) | Some((idx, 'g')) => (&size[..idx], 1 << 30),
+        _ => (size, 1),
+    };
+    num.parse::<u64>()
+        .ok()
+        .filter(|&n| n > 0)
+        .and_then(|n| n.checked_mul(unit))
+}
+
+// Parses a replay mode: all, none, start or a number of lines
+pub fn parse_replay(mode: &str) -> Option<Replay> {
+    match mode {
+        "all" => Some(Replay::All),
+        "none" => Some(Replay::Nothing),
+        "start" => Some(Replay::SinceStart),
+        _ => mode.parse::<usize>().ok().map(Replay::Lines),
+    }
+}
+
+pub fn parse_slow_clients(policy: &str) -> Option<SlowClient> {
+    match policy {
+        "skip" => Some(SlowClient::Skip),
+        "disconnect" => Some(SlowClient::Disconnect),
+        _ => None,
+    }
+}
+
+// Parses a time in seconds with an optional s, m, h or d suffix
+pub fn parse_age(age: &str) -> Option<Duration> {
+    let (num, unit) = match age.char_indices().last() {
+        Some((idx, 's')) => (&age[..idx], 1),
+        Some((idx, 'm')) => (&age[..idx], 60),
+        Some((idx, 'h')) => (&age[..idx], 60 * 60),
+        Some((idx, 'd')) => (&age[..idx], 24 * 60 * 60),
+        _ => (age, 1),
+    };
+    num.parse::<u64>()
+        .ok()
+        .filter(|&n| n > 0)
+        .and_then(|n| n.checked_mul(unit))
+        .map(Duration::from_secs)
+}
+
+pub fn parse_log_level(level: &str) -> Option<LogLevelFilter> {
+    LogLevelFilter::from_str(level).ok()
+}
+
+// Parses a signal argument, exits with a usage error if it is unknown
+fn parse_signal_arg(sig: &str) -> libc::c_int {
+    match parse_signal(sig) {
+        Some(sig) => sig,
+        None => clap::Error::with_description(
+            &format!("Unknown signal '{}'", sig),
+            clap::ErrorKind::InvalidValue,
+        )
+        .exit(),
+    }
+}
+
+const SIGNALS: &[(&str, libc::c_int)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("ABRT", libc::SIGABRT),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("PIPE", libc::SIGPIPE),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TSTP", libc::SIGTSTP),
+    ("WINCH", libc::SIGWINCH),
+];
+
+// Parses a signal name (TERM, SIGTERM, term) or number
+pub fn parse_signal(sig: &str) -> Option<libc::c_int> {
+    if let Ok(num) = sig.parse::<libc::c_int>() {
+        return if num > 0 && num < 65 { Some(num) } else { None };
+    }
+    let upper = sig.to_uppercase();
+    let name = upper.strip_prefix("SIG").unwrap_or(&upper);
+    SIGNALS
+        .iter()
+        .find(|&&(n, _)| n == name)
+        .map(|&(_, num)| num)
+}
+
+pub fn signal_name(sig: libc::c_int) -> String {
+    match SIGNALS.iter().find(|&&(_, num)| num == sig) {
+        Some(&(name, _)) => format!("SIG{}", name),
+        None => format!("signal {}", sig),
+    }
+}
+
 // Parses ^[a-zA-Z] to the correct control code
-fn parse_shortcut(buf: &[u8]) -> Result<Option<u8>, ()> {
+pub fn parse_shortcut(buf: &[u8]) -> Result<Option<u8>, ()> {
     match buf.len() {
         2 if buf[0] == b'^' && buf[1] >= b'A' && buf[1] <= b'z' => Ok(Some(0x1f & buf[1])),
         1 if buf[0] >= b'A' && buf[0] <= b'z' => Ok(Some(buf[0])),
@@ -274,3 +996,120 @@ fn parse_shortcut(buf: &[u8]) -> Result<Option<u8>, ()> {
         _ => Err(()),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tcp(s: &str) -> BindAddr {
+        BindAddr::Tcp(s.parse().unwrap())
+    }
+
+    #[test]
+    fn parse_bind_forms() {
+        assert_eq!(BindAddr::parse_all("3000"), Ok(vec![tcp("127.0.0.1:3000")]));
+        assert_eq!(BindAddr::parse_all("*:3000"), Ok(vec![tcp("0.0.0.0:3000")]));
+        assert_eq!(
+            BindAddr::parse_all("10.0.0.1:3000"),
+            Ok(vec![tcp("10.0.0.1:3000")])
+        );
+        assert_eq!(
+            BindAddr::parse_all("[::1]:3000"),
+            Ok(vec![tcp("[::1]:3000")])
+        );
+        assert_eq!(
+            BindAddr::parse_all("unix:/run/rups.sock"),
+            Ok(vec![BindAddr::Unix(PathBuf::from("/run/rups.sock"))])
+        );
+        let localhost = BindAddr::parse_all("localhost:3000").unwrap();
+        assert!(localhost.iter().any(|a| match *a {
+            BindAddr::Tcp(addr) => addr.ip().is_loopback() && addr.port() == 3000,
+            _ => false,
+        }));
+    }
+
+    #[test]
+    fn parse_sizes_and_ages() {
+        assert_eq!(parse_size("100"), Some(100));
+        assert_eq!(parse_size("10K"), Some(10 * 1024));
+        assert_eq!(parse_size("2M"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_size("0"), None);
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_age("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_age("30m"), Some(Duration::from_secs(30 * 60)));
+        assert_eq!(parse_age("1d"), Some(Duration::from_secs(24 * 60 * 60)));
+        assert_eq!(parse_age("1w"), None);
+    }
+
+    #[test]
+    fn parse_signals() {
+        assert_eq!(parse_signal("TERM"), Some(libc::SIGTERM));
+        assert_eq!(parse_signal("sigint"), Some(libc::SIGINT));
+        assert_eq!(parse_signal("9"), Some(libc::SIGKILL));
+        assert_eq!(parse_signal("FOO"), None);
+        assert_eq!(parse_signal("0"), None);
+        assert_eq!(signal_name(libc::SIGHUP), "SIGHUP");
+    }
+
+    #[test]
+    fn parse_bind_with_replay() {
+        let binds = Bind::parse_all("*:3000,replay=50").unwrap();
+        assert_eq!(binds[0].addr, tcp("0.0.0.0:3000"));
+        assert_eq!(binds[0].replay, Some(Replay::Lines(50)));
+        let binds = Bind::parse_all("unix:/tmp/a,b.sock,replay=start").unwrap();
+        assert_eq!(
+            binds[0].addr,
+            BindAddr::Unix(PathBuf::from("/tmp/a,b.sock"))
+        );
+        assert_eq!(binds[0].replay, Some(Replay::SinceStart));
+        assert_eq!(Bind::parse_all("3000").unwrap()[0].replay, None);
+        assert!(Bind::parse_all("3000,replay=some").is_err());
+    }
+
+    #[test]
+    fn parse_bind_with_binary() {
+        let binds = Bind::parse_all("*:3000,binary,replay=none").unwrap();
+        assert_eq!(binds[0].addr, tcp("0.0.0.0:3000"));
+        assert_eq!(binds[0].replay, Some(Replay::Nothing));
+        assert!(binds[0].binary);
+        let binds = Bind::parse_all("unix:/tmp/a.sock,replay=10,binary").unwrap();
+        assert_eq!(binds[0].replay, Some(Replay::Lines(10)));
+        assert!(binds[0].binary);
+        assert_eq!(binds[0].to_string(), "unix:/tmp/a.sock,replay=10,binary");
+        assert!(!Bind::parse_all("3000").unwrap()[0].binary);
+    }
+
+    #[test]
+    fn parse_bind_errors() {
+        assert!(BindAddr::parse_all("").is_err());
+        assert!(BindAddr::parse_all("70000").is_err());
+        assert!(BindAddr::parse_all("localhost:http").is_err());
+        assert!(BindAddr::parse_all(":3000").is_err());
+        assert!(BindAddr::parse_all("unix:").is_err());
+    }
+
+    #[test]
+    fn detects_shortcut_collisions() {
+        let mut options = Options {
+            replaycmd: Some(0x10),
+            ..Default::default()
+        };
+        assert!(!options.has_shortcut_collision());
+        options.logoutcmd = options.replaycmd;
+        assert!(options.has_shortcut_collision());
+        options.logoutcmd = options.killcmd;
+        assert!(options.has_shortcut_collision());
+    }
+
+    #[test]
+    fn unset_removes_earlier_values() {
+        let mut env = Environment::default();
+        env.add_var(("FOO".to_owned(), "1".to_owned()));
+        env.add_var(("BAR".to_owned(), "2".to_owned()));
+        env.unset_var("FOO".to_owned());
+        assert_eq!(env.set, vec![("BAR".to_owned(), "2".to_owned())]);
+        assert_eq!(env.unset, vec!["FOO"]);
+        env.add_var(("FOO".to_owned(), "3".to_owned()));
+        assert_eq!(env.set.len(), 2);
+    }
+}
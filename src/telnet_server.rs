@@ -1,58 +1,113 @@
-use futures::stream;
-use futures::sync::mpsc;
-use futures::{self, Future, Sink, Stream};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
+use std::collections::BTreeMap;
+use std::fs;
 use std::io;
-use std::net::SocketAddr;
+use std::net;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::os::unix::net as unix;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use std::sync::{Arc, Mutex};
-use std::vec::IntoIter;
-use tokio_core::net::TcpListener;
-use tokio_core::reactor;
-use tokio_io::AsyncRead;
 
-use history::{History, HistoryReader};
+use futures::future::{self, Either, LocalBoxFuture};
+use futures::{stream, FutureExt, SinkExt, Stream, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::sync::{mpsc, oneshot};
+use tokio::task;
+use tokio_util::codec::Framed;
 
-use futures_addition::rx_wrapper::ReceiverWrapper;
-use futures_addition::send_all;
-use rust_telnet::codec::{TelnetCodec, TelnetIn};
-use rust_telnet::codec::{IAC, OPTION};
+use rust_telnet::codec::OPTION;
+use rust_telnet::codec::{TelnetCodec, TelnetIn, TelnetOut};
+use rust_telnet::qstate::QAttitude;
 
-use child;
-use options::Options;
+use crate::child::{self, ProcessHandle};
+use crate::history::{History, HistoryReader, Lag, Replay};
+use crate::options::{self, Bind, BindAddr, Options};
+use crate::util::secs_to_duration;
+
+// Identifies a telnet connection, unix domain socket peers usually have no address of their own.
+pub type ClientId = usize;
+
+/// A connected client, for reporting.
+pub struct ClientInfo {
+    pub peer: String,
+    pub lag: Lag,
+    // Text for this client only, such as notices and prompts
+    pub notices: mpsc::UnboundedSender<Vec<u8>>,
+}
+
+pub type Clients = Rc<RefCell<BTreeMap<ClientId, ClientInfo>>>;
 
 pub struct TelnetServer {
-    process: Arc<Mutex<child::Process>>,
+    process: ProcessHandle,
     history: Rc<RefCell<History>>,
     options: Rc<RefCell<Options>>,
-    listeners: Vec<Box<Future<Item = (), Error = io::Error>>>,
-    tx: mpsc::Sender<Vec<u8>>,
-    rx: ReceiverWrapper<Vec<u8>>,
+    listeners: Vec<LocalBoxFuture<'static, ()>>,
+    next_client: Rc<Cell<ClientId>>,
+    clients: Clients,
+    sockets: Vec<SocketFile>,
 }
 
 impl TelnetServer {
     pub fn new(
         history: Rc<RefCell<History>>,
-        process: Arc<Mutex<child::Process>>,
+        process: ProcessHandle,
         options: Rc<RefCell<Options>>,
     ) -> TelnetServer {
-        // Create a channel for all telnet clients to put their data
-        let (tx, rx) = mpsc::channel(2048);
         TelnetServer {
-            process: process,
-            history: history,
-            options: options,
+            process,
+            history,
+            options,
             listeners: Vec::new(),
-            tx: tx,
-            rx: ReceiverWrapper::new(rx),
+            next_client: Rc::new(Cell::new(0)),
+            clients: Rc::new(RefCell::new(BTreeMap::new())),
+            sockets: Vec::new(),
         }
     }
 
-    pub fn bind(&mut self, addr: &SocketAddr, handle: reactor::Handle, read_only: bool) {
-        let listener = TcpListener::bind(addr, &handle).unwrap();
-        println!("Listening on Port {}", addr);
+    // Has to be called within a tokio runtime
+    pub fn bind(&mut self, bind: &Bind, read_only: bool) -> io::Result<()> {
+        let replay = bind.replay.unwrap_or(self.options.borrow().replay);
+        let addr = &bind.addr;
+        match *addr {
+            BindAddr::Tcp(ref sockaddr) => {
+                let listener = net::TcpListener::bind(sockaddr)?;
+                listener.set_nonblocking(true)?;
+                let listener = TcpListener::from_std(listener)?;
+                let incoming = stream::unfold(listener, |listener| async {
+                    let res = listener.accept().await;
+                    let res = res.map(|(socket, peer_addr)| (socket, peer_addr.to_string()));
+                    Some((res, listener))
+                });
+                self.listen(incoming, read_only, replay, bind.binary);
+            }
+            BindAddr::Unix(ref path) => {
+                remove_stale_socket(path)?;
+                let listener = unix::UnixListener::bind(path)?;
+                self.sockets.push(SocketFile::new(path)?);
+                listener.set_nonblocking(true)?;
+                let listener = UnixListener::from_std(listener)?;
+                let name = addr.to_string();
+                let incoming = stream::unfold(listener, move |listener| {
+                    let name = name.clone();
+                    async move {
+                        let res = listener.accept().await;
+                        Some((res.map(|(socket, _)| (socket, name)), listener))
+                    }
+                });
+                self.listen(incoming, read_only, replay, bind.binary);
+            }
+        }
+        info!("Listening on {}", addr);
+        Ok(())
+    }
+
+    fn listen<I, S>(&mut self, incoming: I, read_only: bool, replay: Replay, binary: bool)
+    where
+        I: Stream<Item = io::Result<(S, String)>> + 'static,
+        S: AsyncRead + AsyncWrite + 'static,
+    {
         let history = self.history.clone();
-        let tx = self.tx.clone();
         let process = self.process.clone();
         let options = self.options.clone();
         // Don't change commands at runtime
@@ -60,147 +115,426 @@ impl TelnetServer {
         let togglecmd = self.options.borrow().togglecmd;
         let restartcmd = self.options.borrow().restartcmd;
         let logoutcmd = self.options.borrow().logoutcmd;
-        let sserver = listener.incoming().for_each(move |(socket, peer_addr)| {
-            println!("Connection {:?}", peer_addr);
-            let (writer, reader) = socket.framed(TelnetCodec::new()).split();
-            let process = process.clone();
-            let process2 = process.clone();
-            let options = options.clone();
-            let options2 = options.clone();
-
-            // Send all outputs from the process to the telnet client
-            let from_process = HistoryReader::new(history.clone());
-            let server = writer
-                .send_all(init_commands())
-                .and_then(move |(rx, _tx)| rx.send_all(motd(options2, process2)))
-                .and_then(|(rx, _tx)| rx.send_all(from_process))
-                .then(|_| Ok(()));
-
-            // Return early if the client is bound to a read only port
-            if read_only {
-                handle.spawn(server);
-                return Ok(());
-            }
+        let replaycmd = self.options.borrow().replaycmd;
+        let info = !self.options.borrow().noinfo;
+        let killsig = self.options.borrow().killsig;
+        let killtimeout = self.options.borrow().killtimeout;
+        let client_queue = self.options.borrow().client_queue;
+        let slow_clients = self.options.borrow().slow_clients;
+        let next_client = self.next_client.clone();
+        let clients = self.clients.clone();
+        let sserver = async move {
+            let mut incoming = Box::pin(incoming);
+            while let Some(res) = incoming.next().await {
+                let (socket, peer_addr) = match res {
+                    Ok(connection) => connection,
+                    Err(e) => {
+                        error!("Failed to accept a connection: {}", e);
+                        break;
+                    }
+                };
+                info!("Connection {}", peer_addr);
+                let client = next_client.get();
+                next_client.set(client + 1);
+                let (mut writer, mut reader) = Framed::new(socket, telnet_codec()).split();
+                let process = process.clone();
+                let process2 = process.clone();
+                let process3 = process.clone();
+                let options = options.clone();
+                let options2 = options.clone();
+                let history = history.clone();
+                let clients = clients.clone();
+                let tx = process.input();
 
-            // Filter out commands from telnet client
-            let reader = reader
-                .filter_map(move |x| {
-                    let process = process.clone();
-                    let options = options.clone();
-                    match x {
-                        TelnetIn::Text { text } => {
-                            if text.len() == 1 {
-                                trace!("Received {:?}", text);
-                                let cmd = text[0];
-                                if let Some(restartcmd) = restartcmd {
-                                    if cmd == restartcmd {
-                                        debug!("Receieved relaunch command");
-                                        let mut process = process.lock().unwrap();
-                                        let _ = process.spawn();
-                                        return None;
+                // Fires when the client logs out, dropping the sender (the client disconnected) has
+                // the same effect.
+                let (logout_tx, logout_rx) = oneshot::channel::<()>();
+                // Answers to the client's negotiation go out with the output
+                let (reply_tx, mut replies) = mpsc::unbounded_channel();
+                let (notice_tx, mut notices) = mpsc::unbounded_channel();
+                let prompt_tx = notice_tx.clone();
+
+                // Send all outputs from the process to the telnet client
+                let mut history_reader = HistoryReader::new(history.clone(), info, replay);
+                history_reader.set_queue_limit(client_queue, slow_clients);
+                let rewind = history_reader.rewinder();
+                clients.borrow_mut().insert(
+                    client,
+                    ClientInfo {
+                        peer: peer_addr.clone(),
+                        lag: history_reader.lag(),
+                        notices: notice_tx,
+                    },
+                );
+                let mut from_process = history_reader.take_until(logout_rx);
+                let output = async move {
+                    writer
+                        .send_all(&mut stream::iter(init_commands(binary)).map(Ok))
+                        .await?;
+                    let started_at = process2.status().await.ok().and_then(|s| s.started_at);
+                    let motd = motd(&options2.borrow(), started_at);
+                    let mut motd = stream::iter(motd).map(|text| Ok(TelnetOut::Text { text }));
+                    writer.send_all(&mut motd).await?;
+                    loop {
+                        tokio::select! {
+                            // The echo of a prompt has to come before the output it asked for
+                            biased;
+                            Some(data) = replies.recv() => {
+                                writer.send(TelnetOut::Reply { data }).await?
+                            }
+                            Some(text) = notices.recv() => {
+                                writer.send(TelnetOut::Text { text }).await?
+                            }
+                            text = from_process.next() => match text {
+                                Some(text) => writer.send(TelnetOut::Text { text }).await?,
+                                None => break,
+                            },
+                        }
+                    }
+                    // Output the reader already holds still goes out before the goodbye
+                    for text in ready_items(from_process.get_mut()) {
+                        writer.send(TelnetOut::Text { text }).await?;
+                    }
+                    let text = b"\r\nGoodbye!\r\n".to_vec();
+                    writer.send(TelnetOut::Text { text }).await
+                };
+
+                let input = async move {
+                    // Set while the client is asked for a replay mode
+                    let mut prompt: Option<ReplayPrompt> = None;
+                    while let Some(res) = reader.next().await {
+                        // A broken connection ends the session like a disconnect
+                        let x = match res {
+                            Ok(x) => x,
+                            Err(e) => {
+                                debug!("Read error: {}", e);
+                                break;
+                            }
+                        };
+                        match x {
+                            TelnetIn::Reply { data } => {
+                                let _ = reply_tx.send(data);
+                            }
+                            TelnetIn::Enabled { option, attitude } => {
+                                debug!("Enabled option {} ({:?})", option, attitude);
+                            }
+                            TelnetIn::Disabled { option, attitude } => {
+                                debug!("Disabled option {} ({:?})", option, attitude);
+                                if (option, attitude) == (OPTION::NAWS, QAttitude::Remote) {
+                                    process.remove_window_size(client);
+                                }
+                            }
+                            TelnetIn::Environment { variables } => {
+                                debug!("Client {} sent environment {:?}", client, variables);
+                            }
+                            // Ignore everything received from clients bound to a read only port
+                            TelnetIn::Text { .. }
+                            | TelnetIn::NAWS { .. }
+                            | TelnetIn::TerminalType { .. }
+                                if read_only => {}
+                            TelnetIn::Text { text } if prompt.is_some() => {
+                                let answer = prompt.as_mut().unwrap().input(&text);
+                                let _ = prompt_tx.send(answer.echo);
+                                if let Some(mode) = answer.mode {
+                                    prompt = None;
+                                    match mode {
+                                        Some(mode) => {
+                                            rewind.set(Some(mode));
+                                            history.borrow_mut().unpark();
+                                        }
+                                        None => {
+                                            let _ = prompt_tx.send(notice("Invalid replay mode"));
+                                        }
                                     }
                                 }
-                                if let Some(togglecmd) = togglecmd {
-                                    if cmd == togglecmd {
+                            }
+                            TelnetIn::Text { text } => {
+                                if text.len() == 1 {
+                                    trace!("Received {:?}", text);
+                                    let cmd = text[0];
+                                    if Some(cmd) == logoutcmd {
+                                        debug!("Received logout command");
+                                        let _ = logout_tx.send(());
+                                        return;
+                                    }
+                                    if Some(cmd) == restartcmd {
+                                        debug!("Receieved relaunch command");
+                                        let process = process.clone();
+                                        task::spawn_local(async move {
+                                            // Refused while the old processes are alive
+                                            child::kill_leftovers(process.clone()).await;
+                                            let _ = process.spawn().await;
+                                        });
+                                        continue;
+                                    }
+                                    if Some(cmd) == togglecmd {
                                         options.borrow_mut().toggle_autorestart();
                                         debug!("Receieved toggle autorestart command");
-                                        return None;
+                                        let autorestart = options.borrow().autorestart;
+                                        history.borrow_mut().event(format!(
+                                            "Auto restart is {}",
+                                            if autorestart { "on" } else { "off" }
+                                        ));
+                                        continue;
                                     }
-                                }
-                                if let Some(killcmd) = killcmd {
-                                    if cmd == killcmd {
+                                    if Some(cmd) == replaycmd {
+                                        debug!("Received replay command");
+                                        let _ = prompt_tx.send(REPLAY_PROMPT.to_vec());
+                                        prompt = Some(ReplayPrompt::default());
+                                        continue;
+                                    }
+                                    if Some(cmd) == killcmd {
                                         debug!("Received kill command");
-                                        let mut process = process.lock().unwrap();
-                                        process.kill().unwrap();
-                                        return None;
+                                        kill_child(process.clone(), killsig, killtimeout);
+                                        continue;
                                     }
                                 }
-                                if let Some(logoutcmd) = logoutcmd {
-                                    if cmd == logoutcmd {
-                                        debug!("Received logout command");
-                                        return Some("TODO".as_bytes().to_vec());
-                                    }
+                                if tx.send(text).await.is_err() {
+                                    break;
                                 }
                             }
-                            return Some(text);
-                        }
-                        TelnetIn::NAWS { rows, columns } => {
-                            process.lock().unwrap().set_window_size(
-                                peer_addr,
-                                (From::from(rows), From::from(columns)),
-                            );
+                            TelnetIn::NAWS { rows, columns } => {
+                                process.set_window_size(
+                                    client,
+                                    (From::from(rows), From::from(columns)),
+                                );
+                            }
+                            TelnetIn::TerminalType { name } => {
+                                debug!("Client {} has terminal type {}", client, name);
+                                process.set_terminal_type(client, name);
+                            }
+                            TelnetIn::Carriage => trace!("CR"),
                         }
-                        TelnetIn::Carriage => println!("CR"),
                     }
-                    None
-                })
-                .map_err(|_| unimplemented!());
-
-            // Create a new sender endpoint where this telnet client can
-            // send all its outputs
-            let tx = tx.clone();
-            let responses = tx.send_all(reader).map_err(|_| ());
-            let server = server.join(responses).map(|_| ());
-            handle.spawn(server);
-            Ok(())
-        });
-        self.listeners.push(Box::new(sserver))
-    }
-
-    pub fn server(self, handle: reactor::Handle) -> Box<Future<Item = (), Error = ()>> {
-        let child_writers = child::ProcessWriters::new(self.process.clone());
-        let rx = self
-            .rx
-            .map_err(|_| io::Error::new(io::ErrorKind::Other, "mupp"));
-        let x = child_writers
-            .fold(rx, move |rx, writer| {
-                send_all::new(writer, rx).then(|result| {
-                    let (_, mut rx, reason) = result.unwrap();
-                    match reason {
-                        send_all::Reason::StreamEnded => {
-                            Err(io::Error::new(io::ErrorKind::Other, "stream ended"))
-                        }
-                        send_all::Reason::SinkEnded { last_item } => {
-                            if let Some(item) = last_item {
-                                rx.get_mut().undo(item);
-                            }
-                            Ok(rx)
+                    drop(logout_tx);
+                };
+
+                task::spawn_local(async move {
+                    let output = Box::pin(output);
+                    let input = Box::pin(input);
+                    match future::select(output, input).await {
+                        // The output side is done, which ends the input side as well
+                        Either::Left(..) => (),
+                        Either::Right((_, output)) => {
+                            let _: io::Result<()> = output.await;
                         }
                     }
-                })
-            })
-            .map_err(|_| ())
-            .map(|_| ());
-        let server = futures::future::join_all(self.listeners)
-            .map(|_| ())
-            .map_err(|_| ());
-        handle.spawn(server);
-        Box::new(x)
+                    info!("Disconnected {}", peer_addr);
+                    process3.remove_window_size(client);
+                    process3.remove_terminal_type(client);
+                    clients.borrow_mut().remove(&client);
+                });
+            }
+        };
+        self.listeners.push(sserver.boxed_local());
+    }
+
+    // Serves the clients, has to run on a LocalSet
+    pub async fn server(self) {
+        future::join_all(self.listeners).await;
+    }
+
+    // The socket files created by `bind`, they should be removed on shutdown
+    pub fn sockets(&self) -> Vec<SocketFile> {
+        self.sockets.clone()
+    }
+
+    pub fn remove_sockets(&self) {
+        for socket in &self.sockets {
+            socket.remove();
+        }
+    }
+
+    pub fn clients(&self) -> Clients {
+        self.clients.clone()
     }
 
     pub fn tx(&self) -> mpsc::Sender<Vec<u8>> {
-        self.tx.clone()
+        self.process.input()
     }
 }
 
-fn init_commands() -> stream::IterOk<IntoIter<Vec<u8>>, io::Error> {
-    stream::iter_ok(vec![
-        vec![IAC::IAC, IAC::WILL, OPTION::ECHO],
-        vec![IAC::IAC, IAC::WILL, OPTION::SUPPRESS_GO_AHEAD],
-        vec![IAC::IAC, IAC::DO, OPTION::NAWS],
-    ])
+// Sends the kill signal to the child. If a kill timeout is configured the child is killed with
+// SIGKILL when it is still alive after the timeout.
+fn kill_child(process: ProcessHandle, killsig: libc::c_int, killtimeout: Option<f64>) {
+    match killtimeout {
+        Some(timeout) if killsig != libc::SIGKILL => {
+            let timeout = secs_to_duration(timeout);
+            task::spawn_local(child::stop(
+                process,
+                killsig,
+                timeout,
+                std::future::pending(),
+            ));
+        }
+        _ => {
+            task::spawn_local(async move {
+                if let Err(e) = process.signal(killsig).await {
+                    warn!("Failed to kill child: {}", e);
+                }
+            });
+        }
+    }
 }
-pub fn motd(
-    options: Rc<RefCell<Options>>,
-    process: Arc<Mutex<child::Process>>,
-) -> stream::IterOk<IntoIter<Vec<u8>>, io::Error> {
-    let child_started_at = if let Some(started_at) = process.lock().unwrap().started_at() {
-        started_at.clone()
-    } else {
-        "Not started yet".to_owned()
-    };
-    let options = options.borrow();
-    stream::iter_ok(vec![
+
+// Sends `message` to every connected client directly, also to those without info lines
+pub fn notify(clients: &Clients, message: &str) {
+    for client in clients.borrow().values() {
+        let _ = client.notices.send(notice(message));
+    }
+}
+
+// A message shown like the info lines of the history
+fn notice(message: &str) -> Vec<u8> {
+    format!("\x1B[33m{}\x1B[0m\r\n", message).into_bytes()
+}
+
+const REPLAY_PROMPT: &[u8] =
+    b"\r\n\x1B[33mReplay all, start, none or a number of lines [all]: \x1B[0m";
+
+// The line a client types after the replay command. The client doesn't echo, so it is echoed
+// here.
+#[derive(Default)]
+struct ReplayPrompt {
+    line: Vec<u8>,
+}
+
+struct PromptAnswer {
+    echo: Vec<u8>,
+    // Set once the line is complete, None inside if it is not a valid replay mode
+    mode: Option<Option<Replay>>,
+}
+
+impl ReplayPrompt {
+    fn input(&mut self, text: &[u8]) -> PromptAnswer {
+        let mut echo = Vec::new();
+        for &byte in text {
+            match byte {
+                b'\r' | b'\n' => {
+                    echo.extend_from_slice(b"\r\n");
+                    let line = String::from_utf8_lossy(&self.line);
+                    let mode = match line.trim() {
+                        "" => Some(Replay::All),
+                        mode => options::parse_replay(mode),
+                    };
+                    return PromptAnswer {
+                        echo,
+                        mode: Some(mode),
+                    };
+                }
+                // Backspace or delete
+                0x08 | 0x7f if !self.line.is_empty() => {
+                    self.line.pop();
+                    echo.extend_from_slice(b"\x08 \x08");
+                }
+                byte if byte.is_ascii_graphic() || byte == b' ' => {
+                    self.line.push(byte);
+                    echo.push(byte);
+                }
+                _ => (),
+            }
+        }
+        PromptAnswer { echo, mode: None }
+    }
+}
+
+// Takes the items that are available from `stream` without waiting for more
+fn ready_items<S: Stream + Unpin>(stream: &mut S) -> Vec<S::Item> {
+    let mut items = Vec::new();
+    while let Some(Some(item)) = stream.next().now_or_never() {
+        items.push(item);
+    }
+    items
+}
+
+/// A unix domain socket file created by this server.
+#[derive(Clone)]
+pub struct SocketFile {
+    path: PathBuf,
+    // Identify the file, another server may have replaced it in the meantime
+    dev: u64,
+    ino: u64,
+}
+
+impl SocketFile {
+    fn new(path: &Path) -> io::Result<SocketFile> {
+        let meta = fs::symlink_metadata(path)?;
+        Ok(SocketFile {
+            path: path.to_owned(),
+            dev: meta.dev(),
+            ino: meta.ino(),
+        })
+    }
+
+    // Removes the file unless it is not ours anymore
+    pub fn remove(&self) {
+        match fs::symlink_metadata(&self.path) {
+            Ok(ref meta) if (meta.dev(), meta.ino()) == (self.dev, self.ino) => {
+                if let Err(e) = fs::remove_file(&self.path) {
+                    warn!("Failed to remove {}: {}", self.path.display(), e);
+                }
+            }
+            Ok(..) => debug!("{} was replaced, not removing it", self.path.display()),
+            Err(e) => debug!("Not removing {}: {}", self.path.display(), e),
+        }
+    }
+}
+
+// Remove a socket file left behind by a previous instance. Refuse to touch anything else,
+// including the socket of a server that is still running.
+fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(ref meta) if meta.file_type().is_socket() => match unix::UnixStream::connect(path) {
+            Ok(..) => Err(io::Error::new(
+                io::ErrorKind::AddrInUse,
+                format!("{} is in use by another process", path.display()),
+            )),
+            Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => fs::remove_file(path),
+            Err(e) => Err(e),
+        },
+        Ok(..) => Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        )),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// The options we ask every client for, all others are refused
+const OPTIONS: [(u8, QAttitude); 5] = [
+    (OPTION::ECHO, QAttitude::Local),
+    (OPTION::SUPPRESS_GO_AHEAD, QAttitude::Local),
+    (OPTION::NAWS, QAttitude::Remote),
+    (OPTION::TERMINAL_TYPE, QAttitude::Remote),
+    (OPTION::NEW_ENVIRON, QAttitude::Remote),
+];
+
+// NAWS, TERMINAL-TYPE and NEW-ENVIRON have endpoints in the codec already, the others carry no
+// data
+fn telnet_codec() -> TelnetCodec {
+    let mut codec = TelnetCodec::new();
+    codec.support(OPTION::ECHO, QAttitude::Local);
+    codec.support(OPTION::SUPPRESS_GO_AHEAD, QAttitude::Local);
+    codec
+}
+
+// Clients of a binary bind are asked for an 8-bit clean session, the others may ask for it
+fn init_commands(binary: bool) -> Vec<TelnetOut> {
+    let mut options = OPTIONS.to_vec();
+    if binary {
+        options.push((OPTION::TRANSMIT_BINARY, QAttitude::Local));
+        options.push((OPTION::TRANSMIT_BINARY, QAttitude::Remote));
+    }
+    options
+        .into_iter()
+        .map(|(option, attitude)| TelnetOut::Enable { option, attitude })
+        .collect()
+}
+
+pub fn motd(options: &Options, child_started_at: Option<String>) -> Vec<Vec<u8>> {
+    let child_started_at = child_started_at.unwrap_or_else(|| "Not started yet".to_owned());
+    vec![
         b"\x1B[33m".to_vec(),
         b"Welcome to Simple Process Server 0.0.1\r\n".to_vec(),
         format!(
@@ -220,7 +554,20 @@ pub fn motd(
         )
         .into_bytes(),
         format!("{} to logout\r\n", format_shortcut(options.logoutcmd)).into_bytes(),
+        match options.replaycmd {
+            Some(..) => format!(
+                "{} to replay the history\r\n",
+                format_shortcut(options.replaycmd)
+            )
+            .into_bytes(),
+            None => Vec::new(),
+        },
         format!("Child working dir: {}\r\n", options.chdir.display()).into_bytes(),
+        if options.env.is_empty() {
+            Vec::new()
+        } else {
+            format!("Child environment: {}\r\n", options.env).into_bytes()
+        },
         b"The server was started at: ".to_vec(),
         options.started_at.as_bytes().to_vec(),
         b"\r\n".to_vec(),
@@ -228,7 +575,7 @@ pub fn motd(
         child_started_at.as_bytes().to_vec(),
         b"\r\n".to_vec(),
         b"\x1B[0m".to_vec(),
-    ])
+    ]
 }
 
 pub fn format_shortcut(cmd: Option<u8>) -> String {
@@ -247,3 +594,46 @@ pub fn format_shortcut(cmd: Option<u8>) -> String {
         None => String::from("disabled"),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use futures::{future, stream, StreamExt};
+
+    use super::{ready_items, ReplayPrompt};
+    use crate::history::Replay;
+
+    #[test]
+    fn ready_items_flushes_after_take_until() {
+        let mut s = stream::iter(vec![1, 2, 3]).take_until(future::ready(()));
+        let taken: Vec<u32> = futures::executor::block_on(s.by_ref().collect());
+        assert!(taken.is_empty());
+        assert_eq!(ready_items(s.get_mut()), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn ready_items_does_not_wait() {
+        let mut s = stream::iter(vec![1]).chain(stream::pending());
+        assert_eq!(ready_items(&mut s), vec![1]);
+    }
+
+    #[test]
+    fn reads_the_replay_mode() {
+        let mut prompt = ReplayPrompt::default();
+        let answer = prompt.input(b"5x");
+        assert_eq!(answer.echo, b"5x");
+        assert_eq!(answer.mode, None);
+        let answer = prompt.input(b"\x7f0\r");
+        assert_eq!(answer.echo, b"\x08 \x080\r\n");
+        assert_eq!(answer.mode, Some(Some(Replay::Lines(50))));
+
+        assert_eq!(
+            ReplayPrompt::default().input(b"\r").mode,
+            Some(Some(Replay::All))
+        );
+        assert_eq!(
+            ReplayPrompt::default().input(b"start\r").mode,
+            Some(Some(Replay::SinceStart))
+        );
+        assert_eq!(ReplayPrompt::default().input(b"some\r").mode, Some(None));
+    }
+}
@@ -0,0 +1,90 @@
+use std::ffi::{CStr, CString};
+
+use pty::Credentials;
+
+struct User {
+    name: CString,
+    uid: libc::uid_t,
+    gid: libc::gid_t,
+}
+
+/// Resolves `--user` and `--group`, both accept a name or a numeric id. The user's primary group
+/// and supplementary groups are used unless a group is given.
+pub fn lookup(user: Option<&str>, group: Option<&str>) -> Result<Option<Credentials>, String> {
+    let gid = match group {
+        Some(group) => Some(lookup_group(group)?),
+        None => None,
+    };
+    match user {
+        Some(user) => {
+            let user = lookup_user(user)?;
+            let gid = gid.unwrap_or(user.gid);
+            let groups = group_list(&user, gid)?;
+            Ok(Some(Credentials {
+                uid: user.uid,
+                gid,
+                groups,
+            }))
+        }
+        None => Ok(gid.map(|gid| Credentials {
+            uid: unsafe { libc::getuid() },
+            gid,
+            groups: vec![gid],
+        })),
+    }
+}
+
+fn lookup_user(user: &str) -> Result<User, String> {
+    // Only called while parsing the arguments, there are no other threads yet
+    let pw = match user.parse::<libc::uid_t>() {
+        Ok(uid) => unsafe { libc::getpwuid(uid) },
+        Err(..) => {
+            let name = CString::new(user).map_err(|_| format!("Invalid user '{}'", user))?;
+            unsafe { libc::getpwnam(name.as_ptr()) }
+        }
+    };
+    if pw.is_null() {
+        return Err(format!("Unknown user '{}'", user));
+    }
+    unsafe {
+        Ok(User {
+            name: CStr::from_ptr((*pw).pw_name).to_owned(),
+            uid: (*pw).pw_uid,
+            gid: (*pw).pw_gid,
+        })
+    }
+}
+
+fn lookup_group(group: &str) -> Result<libc::gid_t, String> {
+    if let Ok(gid) = group.parse::<libc::gid_t>() {
+        return Ok(gid);
+    }
+    let name = CString::new(group).map_err(|_| format!("Invalid group '{}'", group))?;
+    let gr = unsafe { libc::getgrnam(name.as_ptr()) };
+    if gr.is_null() {
+        return Err(format!("Unknown group '{}'", group));
+    }
+    Ok(unsafe { (*gr).gr_gid })
+}
+
+// Supplementary groups of `user`, including `gid`
+fn group_list(user: &User, gid: libc::gid_t) -> Result<Vec<libc::gid_t>, String> {
+    let mut groups: Vec<libc::gid_t> = vec![0; 32];
+    loop {
+        let mut ngroups = groups.len() as libc::c_int;
+        let res = unsafe {
+            libc::getgrouplist(user.name.as_ptr(), gid, groups.as_mut_ptr(), &mut ngroups)
+        };
+        if res >= 0 {
+            groups.truncate(ngroups as usize);
+            return Ok(groups);
+        }
+        if ngroups as usize <= groups.len() {
+            return Err(format!(
+                "Failed to get the groups of '{}'",
+                user.name.to_string_lossy()
+            ));
+        }
+        groups.resize(ngroups as usize, 0);
+    }
+}
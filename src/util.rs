@@ -1,6 +1,43 @@
-// Convert a vector of bytes to a string with hex notation.
 #![allow(dead_code)]
+use std::time::Duration;
+
+// Convert a vector of bytes to a string with hex notation.
 pub fn to_hex_string(bytes: Vec<u8>) -> String {
     let strs: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
     strs.join(" ")
 }
+
+// Convert fractional seconds from the command line to a Duration
+pub fn secs_to_duration(secs: f64) -> Duration {
+    let sec = secs.floor();
+    let nsec = (secs - sec) * 1_000_000_000f64;
+    Duration::new(sec as u64, nsec as u32)
+}
+
+// A scratch directory for tests, removed again when dropped
+#[cfg(test)]
+pub struct TempDir(std::path::PathBuf);
+
+#[cfg(test)]
+pub fn tempdir(name: &str) -> TempDir {
+    let dir = std::env::temp_dir().join(format!("rups-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    TempDir(dir)
+}
+
+#[cfg(test)]
+impl std::ops::Deref for TempDir {
+    type Target = std::path::Path;
+
+    fn deref(&self) -> &std::path::Path {
+        &self.0
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}